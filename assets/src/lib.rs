@@ -3,7 +3,7 @@ pub const TAILWINDJS: &[u8] = include_bytes!("../static/tailwind.js.gz");
 pub const HTMXJS: &[u8] = include_bytes!("../static/htmx.js.gz");
 
 //NOTE : increment this number on adding new icon
-pub const ICONS_SIZE: usize = 15;
+pub const ICONS_SIZE: usize = 16;
 
 macro_rules! build_icons_defs {
     ($($name:ident);*) => {
@@ -23,7 +23,7 @@ macro_rules! build_icons_defs {
     };
 }
 
-build_icons_defs!(Folder; File; Video; Audio; Select; Close; Expand; Collapse; Download; Home; Upload;Up;Down;Retry;Pause);
+build_icons_defs!(Folder; File; Video; Audio; Select; Close; Expand; Collapse; Download; Home; Upload;Up;Down;Retry;Pause;Archive);
 
 impl From<u8> for IconName {
     fn from(value: u8) -> Self {
@@ -0,0 +1,123 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// resolves a client supplied `path` against the shared `root`.
+///
+/// the path must be relative and must stay inside `root` after every symlink
+/// on the way is followed, otherwise a `PermissionDenied` error is returned.
+/// trailing components that do not exist yet (upload targets) are appended as is.
+pub fn resolve(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let mut resolved = root.clone();
+    let mut missing = Vec::new();
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(denied(path));
+            }
+        };
+        if !missing.is_empty() {
+            missing.push(name);
+            continue;
+        }
+        let next = resolved.join(name);
+        match next.canonicalize() {
+            Ok(next) => resolved = next,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // a dangling symlink would let a later create escape the root
+                if fs::symlink_metadata(&next).is_ok() {
+                    return Err(denied(path));
+                }
+                missing.push(name);
+            }
+            Err(err) => return Err(err),
+        }
+        if !resolved.starts_with(&root) {
+            return Err(denied(path));
+        }
+    }
+    Ok(missing
+        .into_iter()
+        .fold(resolved, |acc, name| acc.join(name)))
+}
+
+fn denied(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is outside the shared folder", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    fn shared_root(name: &str) -> PathBuf {
+        let base = temp_dir().join(format!("ours-jail-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root/inner")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(base.join("root/inner/file.txt"), b"ok").unwrap();
+        fs::write(base.join("outside/secret.txt"), b"no").unwrap();
+        base
+    }
+
+    #[test]
+    fn resolves_paths_inside_root() {
+        let base = shared_root("inside");
+        let root = base.join("root");
+        let canonical = root.canonicalize().unwrap();
+        assert_eq!(resolve(&root, Path::new("")).unwrap(), canonical);
+        assert_eq!(
+            resolve(&root, Path::new("./inner/file.txt")).unwrap(),
+            canonical.join("inner/file.txt")
+        );
+        assert_eq!(
+            resolve(&root, Path::new("inner/new/upload.bin")).unwrap(),
+            canonical.join("inner/new/upload.bin")
+        );
+    }
+
+    #[test]
+    fn rejects_parent_and_absolute_paths() {
+        let base = shared_root("hostile");
+        let root = base.join("root");
+        for hostile in [
+            "..",
+            "../outside/secret.txt",
+            "inner/../../outside",
+            "inner/missing/../../..",
+            "/etc/passwd",
+            "/",
+        ] {
+            let err = resolve(&root, Path::new(hostile)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{hostile}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escapes() {
+        use std::os::unix::fs::symlink;
+
+        let base = shared_root("symlink");
+        let root = base.join("root");
+        symlink(base.join("outside"), root.join("out")).unwrap();
+        symlink(base.join("outside/gone.txt"), root.join("dangling")).unwrap();
+        symlink(root.join("inner"), root.join("alias")).unwrap();
+
+        for hostile in ["out", "out/secret.txt", "out/new.txt", "dangling"] {
+            let err = resolve(&root, Path::new(hostile)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{hostile}");
+        }
+        assert_eq!(
+            resolve(&root, Path::new("alias/file.txt")).unwrap(),
+            root.canonicalize().unwrap().join("inner/file.txt")
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub use assets;
+pub mod jail;
 use std::{fmt::Display, net::IpAddr};
 
 #[derive(Debug, Clone)]
@@ -10,7 +11,7 @@ pub struct Origin {
 impl Display for Origin {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let Self { ip, port } = self;
-        write!(f, "http://{ip}:{port}")
+        write!(f, "https://{ip}:{port}")
     }
 }
 
@@ -20,12 +21,20 @@ impl Origin {
     }
 }
 
-#[derive(Default, Clone, Debug)]
-pub enum SelectedState {
-    Copy,
-    Cut,
-    #[default]
-    None,
+pub fn format_size(x: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * KB;
+    const GB: u64 = MB * KB;
+
+    if (0..KB).contains(&x) {
+        format!("{x} B")
+    } else if (KB..MB).contains(&x) {
+        format!("{} KB", x / KB)
+    } else if (MB..GB).contains(&x) {
+        format!("{} MB", x / MB)
+    } else {
+        format!("{} GB", x / GB)
+    }
 }
 
 pub const VIDEO_X: [&str; 39] = [
@@ -38,3 +47,12 @@ pub const AUDIO_X: [&str; 20] = [
     "wav", "mp3", "aiff", "raw", "flac", "alac", "ape", "wv", "tta", "aac", "m4a", "ogg", "opus",
     "wma", "au", "gsm", "amr", "ra", "mmf", "cda",
 ];
+
+/// images the server can render thumbnails of
+pub const IMAGE_X: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff"];
+
+/// archives and image formats that are already compressed
+pub const COMPRESSED_X: [&str; 22] = [
+    "zip", "gz", "tgz", "xz", "txz", "bz2", "zst", "7z", "rar", "lz4", "br", "jpg", "jpeg", "png",
+    "gif", "webp", "avif", "heic", "jxl", "apk", "jar", "deb",
+];
@@ -1,52 +1,184 @@
 use crate::{Page, client::downloads::Downloads, home::go_home_button, svg_from_icon_data};
-use common::assets::IconName;
+use common::{assets::IconName, format_size};
 use grpc::{
     UnitKind,
     client::RpcClient,
     error::RpcError,
-    top::{Selected, Unit},
+    info::{Capabilities, ServerInfo},
+    media::MediaInfo,
+    top::{Selected, SelectedState, Unit},
+    watch::Change,
 };
 use iced::{
     Alignment, Border, Element, Length, Task,
     border::Radius,
+    futures::StreamExt,
     mouse::Interaction,
+    task::{Handle, Straw, sipper},
     theme::Palette,
     widget::{
-        Button, Column, Container, MouseArea, Row, Text, button::Style, container, mouse_area, row,
-        scrollable,
+        Button, Column, Container, MouseArea, Row, Text, button::Style, container, image,
+        mouse_area, row, scrollable,
     },
 };
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 pub mod downloads;
+pub mod ops;
+pub mod search;
 
 #[derive(Clone)]
 pub struct State {
     pub grpc: RpcClient,
+    /// asked for when connecting, actions the server can not do are hidden
+    pub info: ServerInfo,
     pub target: PathBuf,
     pub select: Selected,
     pub units: Vec<Unit>,
+    listing: Option<Handle>,
+    watching: Option<Handle>,
+    /// kept across folders, fetched once a listing is complete
+    thumbnails: HashMap<PathBuf, image::Handle>,
+    thumbnailing: Option<Handle>,
+    /// of the video or audio selected alone
+    media: Option<(PathBuf, MediaInfo)>,
+    edits: ops::Edits,
+    search: search::Search,
+    /// the last call that went wrong, cleared on moving to another folder
+    pub failed: Option<RpcError>,
 }
 
 impl State {
-    pub fn new(grpc: RpcClient) -> Self {
+    pub fn new(grpc: RpcClient, info: ServerInfo) -> Self {
         Self {
             grpc,
+            info,
             target: PathBuf::new(),
             units: Vec::new(),
             select: Selected::default(),
+            listing: None,
+            watching: None,
+            thumbnails: HashMap::new(),
+            thumbnailing: None,
+            media: None,
+            edits: ops::Edits::default(),
+            search: search::Search::default(),
+            failed: None,
         }
     }
+
+    /// moves to `target`, streams its units in as the server sends them
+    /// and keeps them up to date while it stays there
+    pub fn go_to(&mut self, target: PathBuf) -> Task<crate::Message> {
+        if let Some(listing) = self.listing.take() {
+            listing.abort();
+        }
+        if let Some(watching) = self.watching.take() {
+            watching.abort();
+        }
+        if let Some(thumbnailing) = self.thumbnailing.take() {
+            thumbnailing.abort();
+        }
+        self.search.clear();
+        self.units.clear();
+        self.failed = None;
+        self.target = target.clone();
+        let listed = target.clone();
+        let (listing, handle) = Task::sip(
+            list_units(self.grpc.clone(), target.clone()),
+            move |units| Message::UnitsBatch(listed.clone(), units).into(),
+            |result| Message::ListingDone(result).into(),
+        )
+        .abortable();
+        self.listing = Some(handle);
+        if !self.info.can(Capabilities::WATCH) {
+            return listing;
+        }
+        let watched = target.clone();
+        let (watching, handle) = Task::sip(
+            watch_units(self.grpc.clone(), target),
+            move |change| Message::Changed(watched.clone(), change).into(),
+            |result| Message::WatchDone(result).into(),
+        )
+        .abortable();
+        self.watching = Some(handle);
+        Task::batch([listing, watching])
+    }
+
+    /// fetches the missing thumbnails of the listed units one after another
+    fn fetch_thumbnails(&mut self) -> Task<crate::Message> {
+        if !self.info.can(Capabilities::THUMBNAILS) {
+            return Task::none();
+        }
+        let paths = self
+            .units
+            .iter()
+            .filter(|x| x.has_thumbnail() && !self.thumbnails.contains_key(&x.path))
+            .map(|x| x.path.clone())
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Task::none();
+        }
+        let (task, handle) = Task::sip(
+            thumbnails(self.grpc.clone(), paths),
+            |(path, jpeg)| Message::Thumbnail(path, jpeg).into(),
+            |_| Message::ThumbnailsDone.into(),
+        )
+        .abortable();
+        self.thumbnailing = Some(handle);
+        task
+    }
+}
+
+fn list_units(grpc: RpcClient, target: PathBuf) -> impl Straw<(), Vec<Unit>, RpcError> {
+    sipper(async move |mut sender| {
+        let mut batches = grpc.ls_stream(target).await?;
+        while let Some(units) = batches.next().await {
+            sender.send(units?).await;
+        }
+        Ok(())
+    })
+}
+
+fn watch_units(grpc: RpcClient, target: PathBuf) -> impl Straw<(), Change, RpcError> {
+    sipper(async move |mut sender| {
+        let mut changes = grpc.watch(target).await?;
+        while let Some(change) = changes.next().await {
+            sender.send(change?).await;
+        }
+        Ok(())
+    })
+}
+
+/// files the server can not preview keep their icon
+fn thumbnails(grpc: RpcClient, paths: Vec<PathBuf>) -> impl Straw<(), (PathBuf, Vec<u8>), ()> {
+    sipper(async move |mut sender| {
+        for path in paths {
+            if let Ok(jpeg) = grpc.clone().thumbnail(path.clone()).await {
+                sender.send((path, jpeg)).await;
+            }
+        }
+        Ok(())
+    })
 }
 
 #[derive(Clone)]
 pub enum Message {
-    RefreshUnits(Result<Vec<Unit>, RpcError>),
+    UnitsBatch(PathBuf, Vec<Unit>),
+    ListingDone(Result<(), RpcError>),
+    Changed(PathBuf, Change),
+    WatchDone(Result<(), RpcError>),
+    Thumbnail(PathBuf, Vec<u8>),
+    ThumbnailsDone,
+    MediaInfo(PathBuf, Result<MediaInfo, RpcError>),
     UnitClick(Unit),
     UnitDoubleClick(Unit),
     ToggleSelectMode,
     GoToPath(PathBuf),
+    Reload,
     Download(downloads::Message),
+    Ops(ops::Message),
+    Search(search::Message),
 }
 
 impl From<Message> for crate::Message {
@@ -58,8 +190,10 @@ impl From<Message> for crate::Message {
 impl State {
     pub fn view<'a>(&'a self, downloads: &Downloads) -> Element<'a, crate::Message> {
         let tools = self.tools_bar(downloads);
+        let failed = self.failed_line();
+        let media = self.media_line();
         let units = self.units();
-        let all = iced::widget::column![tools, units]
+        let all = iced::widget::column![tools, failed, media, units]
             .spacing(10.)
             .width(Length::Fill);
         Container::new(all)
@@ -70,10 +204,13 @@ impl State {
 
     fn units(&self) -> scrollable::Scrollable<'_, crate::Message> {
         let units = self
-            .units
+            .search
+            .results
+            .as_ref()
+            .unwrap_or(&self.units)
             .iter()
             .fold(Row::new().spacing(10.), |acc, x| {
-                acc.push(x.button(&self.select))
+                acc.push(x.button(&self.select, self.thumbnails.get(&x.path)))
             })
             .wrap();
         let units = Container::new(units)
@@ -94,31 +231,65 @@ impl State {
         scrollable(units).height(Length::Fill).width(Length::Fill)
     }
 
+    /// reloading is offered only when the failure may pass on its own
+    fn failed_line(&self) -> Option<Row<'_, crate::Message>> {
+        let err = self.failed.as_ref()?;
+        let text = Text::new(err.to_string())
+            .size(14.)
+            .style(|theme: &iced::Theme| iced::widget::text::Style {
+                color: Some(theme.palette().danger),
+            });
+        let reload = err
+            .kind()
+            .is_retryable()
+            .then(|| Button::new("reload").on_press(Message::Reload.into()));
+        Some(row![text, reload].spacing(10.).align_y(Alignment::Center))
+    }
+
+    fn media_line(&self) -> Option<Text<'_>> {
+        let (path, info) = self.media.as_ref()?;
+        match self.select.units.as_slice() {
+            [unit] if &unit.path == path => Some(Text::new(info.to_string()).size(14.)),
+            _ => None,
+        }
+    }
+
     fn tools_bar(&self, downloads: &Downloads) -> Container<'_, crate::Message> {
         let home = self.home_button();
         let back = self.back_button();
         let selector = self.select_button();
         let download = self.download_button(downloads);
-        Container::new(row![selector, back, home, download].spacing(5.).wrap())
-            .style(|theme| {
-                let Palette { primary, .. } = theme.palette();
-                container::Style {
-                    border: Border {
-                        width: 1.,
-                        radius: Radius::new(20),
-                        color: primary,
-                    },
-                    ..Default::default()
-                }
-            })
-            .center_x(Length::Fill)
-            .padding(12.)
+        let ops = self.ops_tools();
+        let search = self
+            .info
+            .can(Capabilities::SEARCH)
+            .then(|| self.search_tools());
+        Container::new(
+            row![selector, back, home, download, ops, search]
+                .spacing(5.)
+                .align_y(Alignment::Center)
+                .wrap(),
+        )
+        .style(|theme| {
+            let Palette { primary, .. } = theme.palette();
+            container::Style {
+                border: Border {
+                    width: 1.,
+                    radius: Radius::new(20),
+                    color: primary,
+                },
+                ..Default::default()
+            }
+        })
+        .center_x(Length::Fill)
+        .padding(12.)
     }
 
     fn download_button(&self, downloads: &Downloads) -> Column<'_, crate::Message> {
         let ad = downloads.active_count();
         let active_downloads = (ad != 0).then_some(Text::new(ad));
-        let msg: crate::Message = if self.select.on && !self.select.units.is_empty() {
+        let queue = self.select.on && !self.select.units.is_empty();
+        let msg: crate::Message = if queue {
             downloads::Message::QueueFromSelectedStart.into()
         } else {
             downloads::Message::TogglePreview.into()
@@ -150,14 +321,28 @@ impl State {
 }
 
 trait UnitViews {
-    fn button<'a>(&'a self, selected: &'a Selected) -> MouseArea<'a, crate::Message>;
+    fn button<'a>(
+        &'a self,
+        selected: &'a Selected,
+        thumbnail: Option<&image::Handle>,
+    ) -> MouseArea<'a, crate::Message>;
 }
 
 impl UnitViews for Unit {
-    fn button<'a>(&'a self, selected: &'a Selected) -> MouseArea<'a, crate::Message> {
-        let svg = svg_from_icon_data(self.icon());
-        let text = Text::new(self.name());
-        let row = row![svg, text].spacing(4.);
+    fn button<'a>(
+        &'a self,
+        selected: &'a Selected,
+        thumbnail: Option<&image::Handle>,
+    ) -> MouseArea<'a, crate::Message> {
+        let icon: Element<'a, crate::Message> = match thumbnail {
+            Some(thumbnail) => image(thumbnail.clone()).width(30.).height(30.).into(),
+            None => svg_from_icon_data(self.icon()).into(),
+        };
+        let name = Text::new(self.name());
+        let size = (self.kind != UnitKind::Folder)
+            .then(|| Text::new(format_size(self.meta.size)).size(12.));
+        let text = iced::widget::column![name, size];
+        let row = row![icon, text].spacing(4.).align_y(Alignment::Center);
         mouse_area(Button::new(row).style(|theme, _| {
             let selected = selected.is_selected(self);
             let Palette {
@@ -202,31 +387,82 @@ impl crate::State {
             unreachable!()
         };
         match msg {
-            Message::RefreshUnits(units) => {
-                match units {
-                    Ok(units) => {
-                        state.units = units;
-                    }
+            Message::UnitsBatch(target, units) => {
+                if target == state.target {
+                    state.units.extend(units);
+                    state.units.sort_by_key(|x| (x.kind, x.name()));
+                }
+                Task::none()
+            }
+            Message::ListingDone(result) => {
+                state.listing = None;
+                match result {
+                    Ok(()) => state.fetch_thumbnails(),
                     Err(err) => {
-                        dbg!(err);
+                        state.failed = Some(err);
+                        Task::none()
                     }
                 }
+            }
+            Message::Changed(target, change) => {
+                if target == state.target {
+                    change.apply(&mut state.units);
+                    state.units.sort_by_key(|x| (x.kind, x.name()));
+                }
+                Task::none()
+            }
+            Message::WatchDone(result) => {
+                state.watching = None;
+                if let Err(err) = result {
+                    state.failed = Some(err);
+                }
+                Task::none()
+            }
+            Message::Thumbnail(path, jpeg) => {
+                state
+                    .thumbnails
+                    .insert(path, image::Handle::from_bytes(jpeg));
+                Task::none()
+            }
+            Message::ThumbnailsDone => {
+                state.thumbnailing = None;
+                Task::none()
+            }
+            Message::MediaInfo(path, result) => {
+                match result {
+                    Ok(info) => state.media = Some((path, info)),
+                    Err(err) => state.failed = Some(err),
+                }
                 Task::none()
             }
             Message::UnitClick(unit) => {
+                // the selection waits for its paste
+                if !matches!(state.select.state, SelectedState::None) {
+                    return Task::none();
+                }
                 if state.select.on {
                     state.select.toggle_unit_selection(&unit);
                 } else {
                     state.select.toggle_unit_alone_selection(&unit);
                 }
-                Task::none()
+                let alone = matches!(state.select.units.as_slice(), [x] if x.path == unit.path);
+                let known = state.media.as_ref().is_some_and(|(x, _)| *x == unit.path);
+                let probed = state.info.can(Capabilities::MEDIA_INFO)
+                    && matches!(unit.kind, UnitKind::Video | UnitKind::Audio);
+                if alone && !known && probed {
+                    let path = unit.path;
+                    Task::perform(state.grpc.clone().media_info(path.clone()), move |x| {
+                        Message::MediaInfo(path.clone(), x).into()
+                    })
+                } else {
+                    Task::none()
+                }
             }
             Message::UnitDoubleClick(unit) => match unit.kind {
-                UnitKind::Folder => {
-                    state.target = unit.path.clone();
-                    Task::perform(state.grpc.clone().ls(unit.path.clone()), move |xs| {
-                        Message::RefreshUnits(xs).into()
-                    })
+                UnitKind::Folder => state.go_to(unit.path),
+                // a file found by a search opens the folder that holds it
+                _ if state.search.results.is_some() => {
+                    state.go_to(unit.path.parent().map(PathBuf::from).unwrap_or_default())
                 }
                 _ => {
                     println!("opening file {unit:#?} is not supported yet");
@@ -234,6 +470,7 @@ impl crate::State {
                 }
             },
             Message::ToggleSelectMode => {
+                state.edits = ops::Edits::default();
                 if state.select.on {
                     state.select.clear();
                 } else {
@@ -241,16 +478,14 @@ impl crate::State {
                 }
                 Task::none()
             }
-            Message::GoToPath(path) => {
-                state.target = path.clone();
-                Task::perform(state.grpc.clone().ls(path), |xs| {
-                    Message::RefreshUnits(xs).into()
-                })
-            }
+            Message::GoToPath(path) => state.go_to(path),
+            Message::Reload => state.go_to(state.target.clone()),
             Message::Download(msg) => {
                 let grpc = state.grpc.clone();
                 self.handle_downloads_msg(msg, grpc)
             }
+            Message::Ops(msg) => self.handle_ops_msg(msg),
+            Message::Search(msg) => self.handle_search_msg(msg),
         }
     }
 }
@@ -2,29 +2,36 @@ use crate::{
     Page,
     client::{self, svg_button},
 };
-use common::assets::IconName;
+use common::{assets::IconName, format_size};
 use grpc::{
     UnitKind,
-    client::{DownloadResponse, ResumeDownloadResponse, RpcClient},
+    client::RpcClient,
     error::RpcError,
+    info::{Capabilities, ServerInfo},
     top::Unit,
 };
 use iced::{
     Alignment, Background, Border, Element, Length, Task, Theme,
     border::Radius,
+    futures::{StreamExt, stream::select_all},
     task::{Handle, Straw, sipper},
     widget::{Column, Container, Text, column, container, progress_bar, row, scrollable},
 };
 use std::{
     env::home_dir,
-    path::{Path, PathBuf},
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
     time::{Duration, Instant},
 };
 use tokio::{
     fs::{File, OpenOptions, create_dir_all, remove_file},
-    io::{AsyncWriteExt, BufWriter},
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
 };
 
+/// files at least this big are fetched as parallel ranges
+const SEGMENTED_MIN_SIZE: u64 = 64 * 1024 * 1024;
+const SEGMENTS_COUNT: u64 = 4;
+
 #[derive(Default, Debug, Clone)]
 pub struct Downloads {
     pub show_preview: bool,
@@ -55,7 +62,7 @@ struct Progressing {
 pub enum Message {
     TogglePreview,
     QueueFromSelectedStart,
-    QueueFromSelected(Result<Vec<PathBuf>, RpcError>),
+    QueueFromSelected(Result<Vec<(PathBuf, u64)>, RpcError>),
     Tick(DownloadProgress),
     CancelProgress(usize, Handle),
     Pause(usize, Handle),
@@ -89,12 +96,12 @@ impl crate::State {
                 let paths = match paths {
                     Ok(paths) => paths,
                     Err(err) => {
-                        dbg!(err);
+                        state.failed = Some(err);
                         return Task::none();
                     }
                 };
                 self.downloads.waitlist_extend(paths);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
             Message::Tick(download_progress) => match download_progress {
                 DownloadProgress::Begin { index, total_size } => {
@@ -105,6 +112,12 @@ impl crate::State {
                     self.downloads.files[index].sended += by;
                     Task::none()
                 }
+                DownloadProgress::SegmentProgressed { index, segment, by } => {
+                    let download = &mut self.downloads.files[index];
+                    download.sended += by;
+                    download.segments[segment].done += by as u64;
+                    Task::none()
+                }
                 DownloadProgress::Finish(index) => {
                     self.downloads.finish_list(index);
                     Task::none()
@@ -113,7 +126,7 @@ impl crate::State {
                     if let Err(err) = result {
                         self.downloads.progress_fail_list(index, err);
                     }
-                    self.downloads.tick_available(grpc)
+                    self.downloads.tick_available(grpc, &state.info)
                 }
             },
             Message::TogglePreview => {
@@ -122,23 +135,23 @@ impl crate::State {
             }
             Message::CancelProgress(index, handle) => {
                 handle.abort();
-                Task::perform(
-                    remove_file(join_downloads(&self.downloads.files[index].path)),
-                    move |_| Message::ProgressCanceled(index).into(),
-                )
+                let target = join_downloads(&self.downloads.files[index].path);
+                Task::perform(async move { remove_file(target?).await }, move |_| {
+                    Message::ProgressCanceled(index).into()
+                })
             }
             Message::Pause(index, handle) => {
                 handle.abort();
                 self.downloads.pause_list(index);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
             Message::Resume(index) => {
                 self.downloads.resume_list(index);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
             Message::ProgressCanceled(index) => {
                 self.downloads.progress_cancel_list(index);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
             Message::UpgradePriorty(index) => {
                 self.downloads.upgrade_waiting(index);
@@ -150,11 +163,11 @@ impl crate::State {
             }
             Message::CanceledToWait(index) => {
                 self.downloads.waiting_cancel_list(index);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
             Message::RetryFailed(index) => {
                 self.downloads.fail_wait_list(index);
-                self.downloads.tick_available(grpc)
+                self.downloads.tick_available(grpc, &state.info)
             }
         }
     }
@@ -165,25 +178,48 @@ pub struct Download {
     path: PathBuf,
     total_size: usize,
     sended: usize,
+    segments: Vec<Segment>,
 }
 
-impl From<PathBuf> for Download {
-    fn from(value: PathBuf) -> Self {
+impl From<(PathBuf, u64)> for Download {
+    fn from((path, size): (PathBuf, u64)) -> Self {
         Self {
-            path: value,
-            total_size: 0,
+            path,
+            total_size: size as usize,
             sended: 0,
+            segments: Vec::new(),
         }
     }
 }
 
+/// a byte range of a segmented download, `done` bytes of it are on disk
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    offset: u64,
+    len: u64,
+    done: u64,
+}
+
+fn split_segments(size: u64) -> Vec<Segment> {
+    let len = size.div_ceil(SEGMENTS_COUNT);
+    (0..SEGMENTS_COUNT)
+        .map(|i| i * len)
+        .take_while(|offset| *offset < size)
+        .map(|offset| Segment {
+            offset,
+            len: len.min(size - offset),
+            done: 0,
+        })
+        .collect()
+}
+
 enum Turn {
     Waiting(usize),
     Resumable(usize),
 }
 
 impl Downloads {
-    fn waitlist_extend(&mut self, paths: Vec<PathBuf>) {
+    fn waitlist_extend(&mut self, paths: Vec<(PathBuf, u64)>) {
         let before_len = self.files.len();
         self.files.extend(paths.into_iter().map(Download::from));
         let after_len = self.files.len();
@@ -259,6 +295,7 @@ impl Downloads {
         self.progressing.retain(|x| x.index != index);
         self.failed.push((index, err));
         self.files[index].sended = 0;
+        self.files[index].segments.clear();
     }
 
     fn waiting_cancel_list(&mut self, index: usize) {
@@ -275,6 +312,7 @@ impl Downloads {
         self.progressing.retain(|x| x.index != index);
         self.canceled.push(index);
         self.files[index].sended = 0;
+        self.files[index].segments.clear();
     }
     fn next_turn(&self) -> Option<Turn> {
         if let Some(index) = self.resumable.first() {
@@ -283,42 +321,86 @@ impl Downloads {
             self.waiting.first().map(|index| Turn::Waiting(*index))
         }
     }
-    fn turn_task(&mut self, grpc: RpcClient) -> Option<Task<DownloadProgress>> {
+    /// segments and resuming need ranges, servers without [`Capabilities::RESUME`] send whole files
+    fn turn_task(&mut self, grpc: RpcClient, info: &ServerInfo) -> Option<Task<DownloadProgress>> {
         if self.progressing.len() >= 5 {
             return None;
         }
+        let ranges = info.can(Capabilities::RESUME);
+        let verify = info.can(Capabilities::CHECKSUM);
         match self.next_turn()? {
             Turn::Waiting(index) => {
-                let download = &self.files[index];
-                let (task, handle) = Task::sip(
-                    download_file(grpc.clone(), index, download.path.clone()),
-                    |progress| progress,
-                    move |x| DownloadProgress::CheckDownloadResult { index, result: x },
-                )
-                .abortable();
-
+                let download = &mut self.files[index];
+                let total_size = download.total_size as u64;
+                let (task, handle) = if ranges && total_size >= SEGMENTED_MIN_SIZE {
+                    download.segments = split_segments(total_size);
+                    download_task(
+                        index,
+                        download_segments(
+                            grpc.clone(),
+                            index,
+                            download.path.clone(),
+                            total_size,
+                            download.segments.clone(),
+                            true,
+                            verify,
+                        ),
+                    )
+                } else {
+                    download_task(
+                        index,
+                        download_file(grpc.clone(), index, download.path.clone(), verify),
+                    )
+                };
                 self.wait_progress_list(index, handle);
                 Some(task)
             }
             Turn::Resumable(index) => {
-                let download = &self.files[index];
-                let (task, handle) = Task::sip(
-                    resume_file(grpc.clone(), index, download.sended, download.path.clone()),
-                    |progress| progress,
-                    move |x| DownloadProgress::CheckDownloadResult { index, result: x },
-                )
-                .abortable();
+                let download = &mut self.files[index];
+                let (task, handle) = if !ranges {
+                    // starts over, what is on disk is replaced
+                    download.sended = 0;
+                    download.segments.clear();
+                    download_task(
+                        index,
+                        download_file(grpc.clone(), index, download.path.clone(), verify),
+                    )
+                } else if download.segments.is_empty() {
+                    download_task(
+                        index,
+                        resume_file(
+                            grpc.clone(),
+                            index,
+                            download.sended,
+                            download.path.clone(),
+                            verify,
+                        ),
+                    )
+                } else {
+                    download_task(
+                        index,
+                        download_segments(
+                            grpc.clone(),
+                            index,
+                            download.path.clone(),
+                            download.total_size as u64,
+                            download.segments.clone(),
+                            false,
+                            verify,
+                        ),
+                    )
+                };
                 self.resumable_progress_list(index, handle);
                 Some(task)
             }
         }
     }
 
-    fn tick_available(&mut self, grpc: RpcClient) -> Task<crate::Message> {
+    fn tick_available(&mut self, grpc: RpcClient, info: &ServerInfo) -> Task<crate::Message> {
         let mut xs = Vec::new();
 
         while let Some(task) = self
-            .turn_task(grpc.clone())
+            .turn_task(grpc.clone(), info)
             .map(|task| task.map(move |download_progress| Message::Tick(download_progress).into()))
         {
             xs.push(task);
@@ -366,22 +448,6 @@ impl Downloads {
             return None;
         }
 
-        fn format_size(x: usize) -> String {
-            const KB: usize = 1024;
-            const MB: usize = KB * KB;
-            const GB: usize = MB * MB;
-
-            if (0..KB).contains(&x) {
-                format!("{x} B")
-            } else if (KB..MB).contains(&x) {
-                format!("{} KB", x / KB)
-            } else if (MB..GB).contains(&x) {
-                format!("{} MB", x / MB)
-            } else {
-                format!("{} GB", x / GB)
-            }
-        }
-
         let title = Text::new("in progress downloads");
         let content = column![title];
         let content = self
@@ -396,17 +462,24 @@ impl Downloads {
                     let index = *index;
                     let download = &self.files[index];
                     let path = download.path.display();
-                    let sended = format_size(download.sended);
-                    let total = format_size(download.total_size);
+                    let sended = format_size(download.sended as u64);
+                    let total = format_size(download.total_size as u64);
                     let percent = (download.sended as f32 / download.total_size as f32) * 100.0;
                     let passed = start_instant.elapsed().as_secs();
                     let total_time =((100 * passed) as f32 / percent) as u64;
                     let txt = Text::new(format!(
                         "{path}, {sended} of {total},{percent:.2}% passed time : {passed}second of total : {total_time} second",
                     ));
-                    let progress_bar =
+                    let total_bar =
                         progress_bar(0.0..=(download.total_size as f32), download.sended as f32);
-                    let left = column![txt, progress_bar].align_x(Alignment::Center);
+                    let segments = download
+                        .segments
+                        .iter()
+                        .map(|x| progress_bar(0.0..=(x.len as f32), x.done as f32).girth(4.))
+                        .fold(row![].spacing(3.), |acc, x| acc.push(x));
+                    let left = column![txt, total_bar, segments]
+                        .align_x(Alignment::Center)
+                        .spacing(2.);
                     let cancel = svg_button(IconName::Close.get())
                         .height(Length::Fixed(80.))
                         .clip(false)
@@ -507,13 +580,13 @@ impl Downloads {
             .iter()
             .map(|(index, err)| {
                 let download = &self.files[*index];
-                let txt = Text::new(format!(
-                    "=> {} because of {:#?}",
-                    download.path.display(),
-                    err
-                ));
-                let retry_btn =
-                    svg_button(IconName::Retry.get()).on_press(Message::RetryFailed(*index).into());
+                let txt = Text::new(format!("=> {}: {err}", download.path.display()));
+                // trying again only helps when the failure may pass on its own
+                let retry_btn = svg_button(IconName::Retry.get()).on_press_maybe(
+                    err.kind()
+                        .is_retryable()
+                        .then_some(Message::RetryFailed(*index).into()),
+                );
                 row![txt, retry_btn]
             })
             .fold(content, |acc, x| acc.push(x));
@@ -565,27 +638,33 @@ impl Downloads {
     }
 }
 
-async fn get_download_paths(grpc: RpcClient, units: Vec<Unit>) -> Result<Vec<PathBuf>, RpcError> {
+/// recreates every selected folder under downloads (empty ones too)
+/// and returns the files left to download
+async fn get_download_paths(
+    grpc: RpcClient,
+    units: Vec<Unit>,
+) -> Result<Vec<(PathBuf, u64)>, RpcError> {
     let mut res = Vec::new();
     for unit in units {
         match unit.kind {
             UnitKind::Folder => {
-                let in_units = grpc.clone().ls(unit.path.clone()).await?;
-                let mut folders = Vec::new();
-                for in_unit in in_units {
-                    match in_unit.kind {
-                        UnitKind::Folder => {
-                            folders.push(in_unit);
-                        }
-                        _ => {
-                            res.push(in_unit.path.clone());
+                create_dir_all(join_downloads(&unit.path)?).await?;
+                let mut descendants = grpc.clone().walk(unit.path, None, Vec::new()).await?;
+                while let Some(in_units) = descendants.next().await {
+                    for in_unit in in_units? {
+                        match in_unit.kind {
+                            UnitKind::Folder => {
+                                create_dir_all(join_downloads(&in_unit.path)?).await?;
+                            }
+                            _ => {
+                                res.push((in_unit.path, in_unit.meta.size));
+                            }
                         }
                     }
                 }
-                res.extend(Box::pin(get_download_paths(grpc.clone(), folders)).await?);
             }
             _ => {
-                res.push(unit.path.clone());
+                res.push((unit.path, unit.meta.size));
             }
         };
     }
@@ -602,6 +681,11 @@ pub enum DownloadProgress {
         index: usize,
         by: usize,
     },
+    SegmentProgressed {
+        index: usize,
+        segment: usize,
+        by: usize,
+    },
     Finish(usize),
     CheckDownloadResult {
         index: usize,
@@ -609,17 +693,94 @@ pub enum DownloadProgress {
     },
 }
 
-fn join_downloads(path: &Path) -> PathBuf {
-    home_dir().unwrap().join("Downloads").join(path)
+/// where `path` from the server lands, refusing paths that would leave the downloads folder
+fn join_downloads(path: &Path) -> io::Result<PathBuf> {
+    if !path.components().all(|x| matches!(x, Component::Normal(_))) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the server sent an unsafe path {}", path.display()),
+        ));
+    }
+    Ok(home_dir().unwrap().join("Downloads").join(path))
+}
+
+fn download_task(
+    index: usize,
+    straw: impl Straw<(), DownloadProgress, RpcError> + Send + 'static,
+) -> (Task<DownloadProgress>, Handle) {
+    Task::sip(
+        straw,
+        |progress| progress,
+        move |x| DownloadProgress::CheckDownloadResult { index, result: x },
+    )
+    .abortable()
+}
+
+/// fetches the unfinished part of every segment concurrently into a preallocated file,
+/// `verify` compares checksums with the server once done
+fn download_segments(
+    grpc: RpcClient,
+    index: usize,
+    target: PathBuf,
+    total_size: u64,
+    mut segments: Vec<Segment>,
+    fresh: bool,
+    verify: bool,
+) -> impl Straw<(), DownloadProgress, RpcError> {
+    sipper(async move |mut sender| {
+        let remote = target;
+        let target = join_downloads(&remote)?;
+        if fresh {
+            create_dir_all(target.parent().map(|x| x.to_path_buf()).unwrap_or_default()).await?;
+            let _ = remove_file(&target).await;
+            File::create(&target).await?.set_len(total_size).await?;
+        }
+
+        let mut streams = Vec::new();
+        for (segment, Segment { offset, len, done }) in segments.iter().copied().enumerate() {
+            if done == len {
+                continue;
+            }
+            let stream = grpc
+                .clone()
+                .range_stream(&remote, offset + done, len - done)
+                .await?;
+            streams.push(stream.map(move |chunk| (segment, chunk)));
+        }
+        let mut chunks = select_all(streams);
+
+        let mut file = OpenOptions::new().write(true).open(&target).await?;
+        while let Some((segment, chunk)) = chunks.next().await {
+            let data = chunk?.into_data()?;
+            let Segment { offset, done, .. } = &mut segments[segment];
+            file.seek(SeekFrom::Start(*offset + *done)).await?;
+            file.write_all(&data).await?;
+            *done += data.len() as u64;
+            sender
+                .send(DownloadProgress::SegmentProgressed {
+                    index,
+                    segment,
+                    by: data.len(),
+                })
+                .await;
+        }
+        file.flush().await?;
+        if verify {
+            grpc.verify(&remote, &target).await?;
+        }
+        sender.send(DownloadProgress::Finish(index)).await;
+        Ok(())
+    })
 }
 
 fn download_file(
     grpc: RpcClient,
     index: usize,
     target: PathBuf,
+    verify: bool,
 ) -> impl Straw<(), DownloadProgress, RpcError> {
     sipper(async move |mut sender| {
-        let (size, mut stream) = grpc.download_stream(&target).await?;
+        let (size, mut stream) = grpc.clone().download_stream(&target).await?;
         sender
             .send(DownloadProgress::Begin {
                 index,
@@ -627,7 +788,8 @@ fn download_file(
             })
             .await;
 
-        let target = join_downloads(&target);
+        let remote = target;
+        let target = join_downloads(&remote)?;
         create_dir_all(target.parent().map(|x| x.to_path_buf()).unwrap_or_default()).await?;
         let _ = remove_file(&target).await;
         let file = File::create(&target).await?;
@@ -636,7 +798,8 @@ fn download_file(
             match stream.message().await {
                 Ok(dr) => {
                     match dr {
-                        Some(DownloadResponse { data }) => {
+                        Some(chunk) => {
+                            let data = chunk.into_data()?;
                             file.write_all(&data).await?;
                             file.flush().await?;
                             sender
@@ -647,6 +810,10 @@ fn download_file(
                                 .await;
                         }
                         None => {
+                            file.flush().await?;
+                            if verify {
+                                grpc.verify(&remote, &target).await?;
+                            }
                             sender.send(DownloadProgress::Finish(index)).await;
                             return Ok(());
                         }
@@ -666,10 +833,12 @@ fn resume_file(
     index: usize,
     progress_index: usize,
     target: PathBuf,
+    verify: bool,
 ) -> impl Straw<(), DownloadProgress, RpcError> {
     sipper(async move |mut sender| {
-        let mut stream = grpc.resume_stream(progress_index, &target).await?;
-        let target = join_downloads(&target);
+        let mut stream = grpc.clone().resume_stream(progress_index, &target).await?;
+        let remote = target;
+        let target = join_downloads(&remote)?;
         let file = OpenOptions::new().append(true).open(&target).await?;
         let mut file = BufWriter::new(file);
 
@@ -677,7 +846,8 @@ fn resume_file(
             match stream.message().await {
                 Ok(dr) => {
                     match dr {
-                        Some(ResumeDownloadResponse { data }) => {
+                        Some(chunk) => {
+                            let data = chunk.into_data()?;
                             file.write_all(&data).await?;
                             file.flush().await?;
                             sender
@@ -688,6 +858,10 @@ fn resume_file(
                                 .await;
                         }
                         None => {
+                            file.flush().await?;
+                            if verify {
+                                grpc.verify(&remote, &target).await?;
+                            }
                             sender.send(DownloadProgress::Finish(index)).await;
                             return Ok(());
                         }
@@ -0,0 +1,211 @@
+use crate::{Page, client, home::style_input};
+use grpc::{error::RpcError, info::Capabilities, top::SelectedState};
+use iced::{
+    Element, Task,
+    widget::{Button, Row, text_input::TextInput},
+};
+
+/// pending edits typed or confirmed in the tools bar
+#[derive(Default, Debug, Clone)]
+pub struct Edits {
+    confirm_delete: bool,
+    rename: Option<String>,
+    new_folder: Option<String>,
+}
+
+#[derive(Clone)]
+pub enum Message {
+    Copy,
+    Cut,
+    Paste,
+    CancelPaste,
+    AskDelete,
+    Delete,
+    RenameStart,
+    RenameInput(String),
+    Rename,
+    NewFolderStart,
+    NewFolderInput(String),
+    NewFolder,
+    CancelInput,
+    Done(Result<(), RpcError>),
+}
+
+impl From<Message> for crate::Message {
+    fn from(value: Message) -> Self {
+        crate::Message::Client(client::Message::Ops(value))
+    }
+}
+
+impl client::State {
+    pub(super) fn ops_tools(&self) -> Row<'_, crate::Message> {
+        let Edits {
+            confirm_delete,
+            rename,
+            new_folder,
+        } = &self.edits;
+        let tools: Vec<Element<'_, crate::Message>> = if let Some(name) = new_folder {
+            vec![
+                name_input("new folder name", name, Message::NewFolderInput)
+                    .on_submit(Message::NewFolder.into())
+                    .into(),
+                Button::new("create")
+                    .on_press(Message::NewFolder.into())
+                    .into(),
+                Button::new("cancel")
+                    .on_press(Message::CancelInput.into())
+                    .into(),
+            ]
+        } else if let Some(name) = rename {
+            vec![
+                name_input("new name", name, Message::RenameInput)
+                    .on_submit(Message::Rename.into())
+                    .into(),
+                Button::new("rename")
+                    .on_press(Message::Rename.into())
+                    .into(),
+                Button::new("cancel")
+                    .on_press(Message::CancelInput.into())
+                    .into(),
+            ]
+        } else if !matches!(self.select.state, SelectedState::None) {
+            vec![
+                Button::new("paste here")
+                    .on_press(Message::Paste.into())
+                    .into(),
+                Button::new("cancel")
+                    .on_press(Message::CancelPaste.into())
+                    .into(),
+            ]
+        } else {
+            let selected = self.select.on && !self.select.is_clear();
+            let delete = if *confirm_delete {
+                Button::new("confirm delete").on_press(Message::Delete.into())
+            } else {
+                Button::new("delete").on_press_maybe(selected.then_some(Message::AskDelete.into()))
+            };
+            let mut tools: Vec<Element<'_, crate::Message>> = Vec::new();
+            if self.info.can(Capabilities::UPLOAD) {
+                tools.push(
+                    Button::new("new folder")
+                        .on_press(Message::NewFolderStart.into())
+                        .into(),
+                );
+            }
+            if self.info.can(Capabilities::EDIT) {
+                tools.extend([
+                    Button::new("copy")
+                        .on_press_maybe(selected.then_some(Message::Copy.into()))
+                        .into(),
+                    Button::new("cut")
+                        .on_press_maybe(selected.then_some(Message::Cut.into()))
+                        .into(),
+                    Button::new("rename")
+                        .on_press_maybe(
+                            (self.select.on && self.select.units.len() == 1)
+                                .then_some(Message::RenameStart.into()),
+                        )
+                        .into(),
+                    delete.into(),
+                ]);
+            }
+            tools
+        };
+        Row::from_vec(tools).spacing(5.)
+    }
+}
+
+fn name_input<'a>(
+    placeholder: &'a str,
+    value: &'a str,
+    on_input: fn(String) -> Message,
+) -> TextInput<'a, crate::Message> {
+    TextInput::new(placeholder, value)
+        .padding(7.)
+        .width(200.)
+        .style(|theme, _| style_input(theme))
+        .on_input(move |x| on_input(x).into())
+}
+
+impl crate::State {
+    pub fn handle_ops_msg(&mut self, msg: Message) -> Task<crate::Message> {
+        let Page::Client(state) = &mut self.page else {
+            unreachable!()
+        };
+        let grpc = state.grpc.clone();
+        let done = |x: Result<(), RpcError>| -> crate::Message { Message::Done(x).into() };
+        match msg {
+            Message::Copy => {
+                state.select.copy();
+                Task::none()
+            }
+            Message::Cut => {
+                state.select.cut();
+                Task::none()
+            }
+            Message::CancelPaste => {
+                state.select.none();
+                Task::none()
+            }
+            Message::Paste => {
+                let paths = state.select.as_paths();
+                let destination = state.target.clone();
+                match state.select.state {
+                    SelectedState::Copy => Task::perform(grpc.copy_to(paths, destination), done),
+                    SelectedState::Cut => Task::perform(grpc.move_to(paths, destination), done),
+                    SelectedState::None => Task::none(),
+                }
+            }
+            Message::AskDelete => {
+                state.edits.confirm_delete = true;
+                Task::none()
+            }
+            Message::Delete => {
+                state.edits.confirm_delete = false;
+                let recursive = state.select.has_dirs();
+                Task::perform(grpc.delete(state.select.as_paths(), recursive), done)
+            }
+            Message::RenameStart => {
+                state.edits.rename = state.select.units.first().map(|x| x.name());
+                Task::none()
+            }
+            Message::RenameInput(name) => {
+                state.edits.rename = Some(name);
+                Task::none()
+            }
+            Message::Rename => {
+                let (Some(name), Some(unit)) =
+                    (state.edits.rename.take(), state.select.units.first())
+                else {
+                    return Task::none();
+                };
+                Task::perform(grpc.rename(unit.path.clone(), name), done)
+            }
+            Message::NewFolderStart => {
+                state.edits.new_folder = Some(String::new());
+                Task::none()
+            }
+            Message::NewFolderInput(name) => {
+                state.edits.new_folder = Some(name);
+                Task::none()
+            }
+            Message::NewFolder => match state.edits.new_folder.take() {
+                Some(name) if !name.is_empty() => {
+                    Task::perform(grpc.mkdir(state.target.join(name)), done)
+                }
+                _ => Task::none(),
+            },
+            Message::CancelInput => {
+                state.edits = Edits::default();
+                Task::none()
+            }
+            Message::Done(result) => {
+                state.edits = Edits::default();
+                state.select.clear();
+                let task = state.go_to(state.target.clone());
+                state.failed = result.err();
+                task
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{Page, client, home::style_input};
+use grpc::{client::RpcClient, error::RpcError, search::Query, top::Unit};
+use iced::{
+    Alignment, Element, Task,
+    futures::StreamExt,
+    task::{Handle, Straw, sipper},
+    widget::{Button, Row, Text, text_input::TextInput},
+};
+use std::path::PathBuf;
+
+/// the search typed in the tools bar, results replace the listing while they are shown
+#[derive(Default, Clone)]
+pub struct Search {
+    text: String,
+    pub results: Option<Vec<Unit>>,
+    running: Option<Handle>,
+}
+
+impl Search {
+    pub fn clear(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.abort();
+        }
+        self.results = None;
+    }
+}
+
+#[derive(Clone)]
+pub enum Message {
+    Input(String),
+    Submit,
+    Batch(Vec<Unit>),
+    Done(Result<(), RpcError>),
+    Clear,
+}
+
+impl From<Message> for crate::Message {
+    fn from(value: Message) -> Self {
+        crate::Message::Client(client::Message::Search(value))
+    }
+}
+
+impl client::State {
+    pub(super) fn search_tools(&self) -> Row<'_, crate::Message> {
+        let input = TextInput::new("search", &self.search.text)
+            .padding(7.)
+            .width(200.)
+            .style(|theme, _| style_input(theme))
+            .on_input(|x| Message::Input(x).into())
+            .on_submit(Message::Submit.into());
+        let mut tools: Vec<Element<'_, crate::Message>> = vec![input.into()];
+        if let Some(results) = &self.search.results {
+            let label = if self.search.running.is_some() {
+                format!("{} found so far", results.len())
+            } else {
+                format!("{} found", results.len())
+            };
+            tools.push(Text::new(label).into());
+            tools.push(Button::new("clear").on_press(Message::Clear.into()).into());
+        }
+        Row::from_vec(tools).spacing(5.).align_y(Alignment::Center)
+    }
+}
+
+fn search_units(
+    grpc: RpcClient,
+    target: PathBuf,
+    query: Query,
+) -> impl Straw<(), Vec<Unit>, RpcError> {
+    sipper(async move |mut sender| {
+        let mut batches = grpc.search(target, query).await?;
+        while let Some(units) = batches.next().await {
+            sender.send(units?).await;
+        }
+        Ok(())
+    })
+}
+
+impl crate::State {
+    pub fn handle_search_msg(&mut self, msg: Message) -> Task<crate::Message> {
+        let Page::Client(state) = &mut self.page else {
+            unreachable!()
+        };
+        match msg {
+            Message::Input(text) => {
+                state.search.text = text;
+                Task::none()
+            }
+            Message::Submit => {
+                state.search.clear();
+                if state.search.text.is_empty() {
+                    return Task::none();
+                }
+                state.search.results = Some(Vec::new());
+                let (task, handle) = Task::sip(
+                    search_units(
+                        state.grpc.clone(),
+                        state.target.clone(),
+                        Query::new(state.search.text.clone()),
+                    ),
+                    |units| Message::Batch(units).into(),
+                    |result| Message::Done(result).into(),
+                )
+                .abortable();
+                state.search.running = Some(handle);
+                task
+            }
+            Message::Batch(units) => {
+                if let Some(results) = &mut state.search.results {
+                    results.extend(units);
+                    results.sort_by_key(|x| (x.kind, x.name()));
+                }
+                Task::none()
+            }
+            Message::Done(result) => {
+                state.search.running = None;
+                if let Err(err) = result {
+                    state.failed = Some(err);
+                }
+                Task::none()
+            }
+            Message::Clear => {
+                state.search.clear();
+                Task::none()
+            }
+        }
+    }
+}
@@ -1,17 +1,24 @@
 use std::{
     net::{AddrParseError, IpAddr, SocketAddr},
     path::PathBuf,
+    time::Duration,
 };
 
-use crate::{Page, client, svg_from_icon_data};
+use crate::{Page, client, known_hosts, svg_from_icon_data};
 use common::assets::IconName;
-use grpc::{client::RpcClient, error::RpcError};
+use grpc::{
+    client::RpcClient,
+    discovery::{self, Found},
+    error::RpcError,
+    info::ServerInfo,
+};
 use iced::{
     Alignment, Background, Border, Element, Length, Task,
     border::Radius,
     theme::Palette,
     widget::{
-        Button, Container, Text, button, center, column, container, mouse_area, opaque, row, stack,
+        Button, Column, Container, Text, button, center, column, container, mouse_area, opaque,
+        row, stack, text,
         text_input::{self, Style},
     },
 };
@@ -27,6 +34,18 @@ pub struct UrlForm {
     pub ip: String,
     pub valid_ip: Option<IpAddr>,
     pub port: u16,
+    pub token: String,
+    /// fingerprint the server showed instead of the pinned one
+    pub changed: Option<String>,
+    /// protocol versions of the server and this app when they differ
+    pub incompatible: Option<(u32, u32)>,
+    /// servers that answered the last scan of the network
+    pub nearby: Vec<Found>,
+    pub scanning: bool,
+    /// why the network could not be scanned
+    pub scan_failed: Option<String>,
+    /// why the last connection attempt failed
+    pub failed: Option<RpcError>,
 }
 
 #[derive(Clone)]
@@ -36,9 +55,14 @@ pub enum Message {
         valid_ip: Result<IpAddr, AddrParseError>,
         input_value: String,
     },
-    SubmitInput(IpAddr, u16),
-    PrepareGrpc(Result<RpcClient, RpcError>),
+    TokenNewInput(String),
+    SubmitInput(IpAddr, u16, String),
+    PrepareGrpc(Result<(RpcClient, ServerInfo), RpcError>),
+    TrustChanged,
     ToggleInputModal,
+    Scan,
+    Scanned(Result<Vec<Found>, String>),
+    PickFound(IpAddr, u16),
 }
 
 impl From<Message> for crate::Message {
@@ -88,7 +112,7 @@ impl State {
     }
 }
 
-fn style_input(theme: &iced::Theme) -> Style {
+pub(crate) fn style_input(theme: &iced::Theme) -> Style {
     let Palette {
         background,
         text,
@@ -121,24 +145,66 @@ impl UrlForm {
         let ip_input = self.ip_input();
         let port_input = self.port_input();
         let url_input = row![ip_input, port_input].spacing(10.);
+        let token_input = self.token_input();
 
         let submit = self.submit_button();
         let cancel = self.cancle_button();
         let buttons = row![submit, cancel].spacing(10.);
 
-        let content = column![title, url_input, buttons]
+        let mut content = column![title, self.nearby_section(), url_input, token_input];
+        if let Some(found) = &self.changed {
+            content = content.push(self.changed_warning(found));
+        }
+        if let Some((server, client)) = self.incompatible {
+            content = content.push(incompatible_warning(server, client));
+        }
+        if let Some(err) = &self.failed {
+            content = content.push(self.failed_warning(err));
+        }
+        let content = content
+            .push(buttons)
             .align_x(Alignment::Center)
             .spacing(20.)
             .padding(20.);
         Container::new(content).style(form_style).padding(20.)
     }
 
+    /// one click fills the address of a server found on the network, the token is still typed
+    fn nearby_section(&self) -> Column<'_, crate::Message> {
+        let servers = self.nearby.iter().map(|Found { ip, announcement }| {
+            let label = format!(
+                "{} : {} at {}",
+                announcement.name,
+                announcement.shares.join(", "),
+                SocketAddr::new(*ip, announcement.rpc_port)
+            );
+            Element::from(
+                Button::new(Text::new(label).size(25.))
+                    .on_press(Message::PickFound(*ip, announcement.rpc_port).into()),
+            )
+        });
+        let scan = if self.scanning {
+            Button::new(Text::new("looking for servers...").size(20.))
+        } else {
+            let label = match &self.scan_failed {
+                Some(err) => format!("could not look for servers: {err}, look again"),
+                None if self.nearby.is_empty() => String::from("no servers found, look again"),
+                None => String::from("look again"),
+            };
+            Button::new(Text::new(label).size(20.)).on_press(Message::Scan.into())
+        };
+        Column::from_vec(servers.collect())
+            .push(scan)
+            .align_x(Alignment::Center)
+            .spacing(10.)
+    }
+
     fn submit_button(&self) -> Button<'_, crate::Message> {
         let content = Text::new("submit").size(60.).center();
         Button::new(content).on_press_maybe(
             self.valid_ip
-                .map(|ip| Message::SubmitInput(ip, self.port).into())
-                .take_if(|_| self.port != 0),
+                .map(|ip| Message::SubmitInput(ip, self.port, self.token.clone()).into())
+                .take_if(|_| self.port != 0 && !self.token.is_empty()),
         )
     }
 
@@ -158,6 +224,15 @@ impl UrlForm {
         .on_input(|x| Message::PortNewInput(x.parse::<u16>()).into())
     }
 
+    fn token_input(&self) -> text_input::TextInput<'_, crate::Message> {
+        text_input::TextInput::new("pairing token shown by the server", &self.token)
+            .size(30.)
+            .padding(10.)
+            .align_x(Alignment::Center)
+            .style(|theme, _| style_input(theme))
+            .on_input(|x| Message::TokenNewInput(x).into())
+    }
+
     fn ip_input(&self) -> text_input::TextInput<'_, crate::Message> {
         text_input::TextInput::new("insert ip", &self.ip.to_string())
             .size(30.)
@@ -182,12 +257,63 @@ impl UrlForm {
             })
     }
 
+    fn changed_warning<'a>(&'a self, found: &'a str) -> Column<'a, crate::Message> {
+        let warning = Text::new(
+            "the server certificate changed since the last connection, \
+             someone may be intercepting it. trust the new one only if the server shows",
+        )
+        .size(20.)
+        .center()
+        .style(|theme: &iced::Theme| text::Style {
+            color: Some(theme.palette().danger),
+        });
+        let fingerprint = Text::new(found).size(20.).center();
+        let trust = Button::new(Text::new("trust the new certificate").size(20.))
+            .on_press(Message::TrustChanged.into());
+        column![warning, fingerprint, trust]
+            .align_x(Alignment::Center)
+            .spacing(10.)
+    }
+
+    /// trying again is offered only when the failure may pass on its own
+    fn failed_warning<'a>(&'a self, err: &RpcError) -> Column<'a, crate::Message> {
+        let warning = Text::new(err.to_string())
+            .size(20.)
+            .center()
+            .style(|theme: &iced::Theme| text::Style {
+                color: Some(theme.palette().danger),
+            });
+        let retry = err.kind().is_retryable().then(|| {
+            Button::new(Text::new("try again").size(20.)).on_press_maybe(
+                self.valid_ip
+                    .map(|ip| Message::SubmitInput(ip, self.port, self.token.clone()).into()),
+            )
+        });
+        column![warning, retry]
+            .align_x(Alignment::Center)
+            .spacing(10.)
+    }
+
     fn cancle_button(&self) -> Button<'_, crate::Message> {
         let cancel = Text::new("cancel").size(60.).center();
         Button::new(cancel).on_press(Message::ToggleInputModal.into())
     }
 }
 
+fn incompatible_warning<'a>(server: u32, client: u32) -> Text<'a> {
+    let msg = if server < client {
+        "the server is older than this app, update it to connect"
+    } else {
+        "the server is newer than this app, update the app to connect"
+    };
+    Text::new(msg)
+        .size(20.)
+        .center()
+        .style(|theme: &iced::Theme| text::Style {
+            color: Some(theme.palette().danger),
+        })
+}
+
 fn form_style(theme: &iced::Theme) -> container::Style {
     container::Style {
         border: Border {
@@ -276,24 +402,93 @@ impl crate::State {
                 }
                 Task::none()
             }
-            Message::SubmitInput(ip_addr, port) => {
-                Task::future(RpcClient::new(SocketAddr::new(ip_addr, port)))
-                    .map(|x| Message::PrepareGrpc(x).into())
+            Message::TokenNewInput(token) => {
+                state.url_form.token = token;
+                Task::none()
+            }
+            Message::SubmitInput(ip_addr, port, token) => {
+                state.url_form.changed = None;
+                state.url_form.incompatible = None;
+                state.url_form.failed = None;
+                let pinned = known_hosts::pinned(ip_addr);
+                Task::future(async move {
+                    let grpc =
+                        RpcClient::new(SocketAddr::new(ip_addr, port), &token, pinned).await?;
+                    let info = grpc.clone().server_info().await?;
+                    info.check()?;
+                    Ok((grpc, info))
+                })
+                .map(|x| Message::PrepareGrpc(x).into())
+            }
+            Message::TrustChanged => {
+                let UrlForm {
+                    valid_ip: Some(ip),
+                    port,
+                    token,
+                    changed: Some(found),
+                    ..
+                } = &state.url_form
+                else {
+                    return Task::none();
+                };
+                if let Err(err) = known_hosts::pin(*ip, found) {
+                    state.url_form.failed = Some(err.into());
+                    return Task::none();
+                }
+                Task::done(Message::SubmitInput(*ip, *port, token.clone()).into())
             }
             Message::PrepareGrpc(rpc_client) => match rpc_client {
-                Ok(grpc) => {
-                    self.page = Page::Client(client::State::new(grpc.clone()));
+                Ok((grpc, info)) => {
+                    let pinned = known_hosts::pin(grpc.addr.ip(), &grpc.fingerprint);
+                    let mut client = client::State::new(grpc, info);
+                    let task = client.go_to(PathBuf::new());
+                    // the connection works, the certificate is just asked about again next time
+                    client.failed = pinned.err().map(RpcError::from);
+                    self.page = Page::Client(Box::new(client));
                     self.home.show_form = false;
-                    Task::future(grpc.ls(PathBuf::new()))
-                        .map(|x| client::Message::RefreshUnits(x).into())
+                    task
+                }
+                Err(RpcError::CertificateChanged { found, .. }) => {
+                    state.url_form.changed = Some(found);
+                    Task::none()
+                }
+                Err(RpcError::Incompatible { server, client }) => {
+                    state.url_form.incompatible = Some((server, client));
+                    Task::none()
                 }
                 Err(err) => {
-                    dbg!(err);
+                    state.url_form.failed = Some(err);
                     Task::none()
                 }
             },
             Message::ToggleInputModal => {
                 state.show_form = !state.show_form;
+                if state.show_form {
+                    Task::done(Message::Scan.into())
+                } else {
+                    Task::none()
+                }
+            }
+            Message::Scan => {
+                state.url_form.scanning = true;
+                Task::future(discovery::discover(discovery::PORT, Duration::from_secs(1)))
+                    .map(|found| Message::Scanned(found.map_err(|err| err.to_string())).into())
+            }
+            Message::Scanned(found) => {
+                state.url_form.scanning = false;
+                match found {
+                    Ok(found) => {
+                        state.url_form.nearby = found;
+                        state.url_form.scan_failed = None;
+                    }
+                    Err(err) => state.url_form.scan_failed = Some(err),
+                }
+                Task::none()
+            }
+            Message::PickFound(ip, port) => {
+                state.url_form.ip = ip.to_string();
+                state.url_form.valid_ip = Some(ip);
+                state.url_form.port = port;
                 Task::none()
             }
         }
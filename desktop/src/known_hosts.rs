@@ -0,0 +1,76 @@
+use std::{env::home_dir, fs, io, net::IpAddr, path::PathBuf};
+
+/// where this install keeps its certificate and the servers it trusts
+pub fn config_dir() -> PathBuf {
+    home_dir().unwrap().join(".ours")
+}
+
+/// one "ip fingerprint" per line, servers are known by ip since their ports change on every launch
+fn known_hosts() -> PathBuf {
+    config_dir().join("known_hosts")
+}
+
+fn is_host(line: &str, ip: IpAddr) -> bool {
+    line.split_once(' ')
+        .is_some_and(|(host, _)| host.parse::<IpAddr>().is_ok_and(|x| x == ip))
+}
+
+/// the certificate fingerprint trusted for `ip`, if it was connected to before
+pub fn pinned(ip: IpAddr) -> Option<String> {
+    find_pin(&fs::read_to_string(known_hosts()).ok()?, ip)
+}
+
+/// trusts `fingerprint` for `ip` from now on, replacing what was pinned before
+pub fn pin(ip: IpAddr, fingerprint: &str) -> io::Result<()> {
+    let hosts = fs::read_to_string(known_hosts()).unwrap_or_default();
+    fs::create_dir_all(config_dir())?;
+    fs::write(known_hosts(), with_pin(&hosts, ip, fingerprint))
+}
+
+fn find_pin(hosts: &str, ip: IpAddr) -> Option<String> {
+    hosts
+        .lines()
+        .find(|line| is_host(line, ip))
+        .and_then(|line| line.split_once(' '))
+        .map(|(_, fingerprint)| fingerprint.to_string())
+}
+
+fn with_pin(hosts: &str, ip: IpAddr, fingerprint: &str) -> String {
+    let mut lines = hosts
+        .lines()
+        .filter(|line| !is_host(line, ip))
+        .map(String::from)
+        .collect::<Vec<_>>();
+    lines.push(format!("{ip} {fingerprint}"));
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_hosts_have_no_pin() {
+        let hosts = with_pin("", "10.0.0.1".parse().unwrap(), "aa");
+        assert_eq!(find_pin(&hosts, "10.0.0.2".parse().unwrap()), None);
+        assert_eq!(find_pin("", "10.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn pins_are_found_by_ip() {
+        let hosts = with_pin("", "10.0.0.1".parse().unwrap(), "aa");
+        let hosts = with_pin(&hosts, "::1".parse().unwrap(), "bb");
+        assert_eq!(find_pin(&hosts, "10.0.0.1".parse().unwrap()).unwrap(), "aa");
+        assert_eq!(find_pin(&hosts, "::1".parse().unwrap()).unwrap(), "bb");
+    }
+
+    #[test]
+    fn pinning_again_replaces_the_old_pin() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let hosts = with_pin("", ip, "aa");
+        let hosts = with_pin(&hosts, "10.0.0.10".parse().unwrap(), "cc");
+        let hosts = with_pin(&hosts, ip, "bb");
+        assert_eq!(find_pin(&hosts, ip).unwrap(), "bb");
+        assert_eq!(hosts, "10.0.0.10 cc\n10.0.0.1 bb\n");
+    }
+}
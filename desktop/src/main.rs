@@ -13,12 +13,13 @@ use crate::{
 
 pub mod client;
 pub mod home;
+pub mod known_hosts;
 pub mod server;
 
 #[derive(Clone)]
 pub enum Page {
     Home,
-    Client(client::State),
+    Client(Box<client::State>),
     Server,
 }
 
@@ -1,11 +1,26 @@
-use common::Origin;
-use grpc::server::RpcServer;
-use iced::Task;
+use common::{Origin, format_size};
+use grpc::{
+    ShareMode,
+    discovery::{self, Announcement},
+    server::RpcServer,
+    shares::Shares,
+    tls::Identity,
+    transfers::{Direction, TransferInfo},
+};
+use iced::{
+    Task,
+    futures::stream,
+    task::{Handle, Straw, sipper},
+};
 use std::env::home_dir;
 use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::home::go_home_button;
+use crate::{
+    home::{go_home_button, style_input},
+    known_hosts::config_dir,
+};
 use iced::{
     Alignment::Center,
     Background, Border, Element, Length, Shadow, Theme, Vector,
@@ -14,27 +29,46 @@ use iced::{
     widget::{
         self, Button, Column, Container, Row,
         button::Style,
-        column, qr_code, row,
+        column, progress_bar, qr_code, row,
         text::{self, Wrapping},
+        text_input::{self, TextInput},
     },
 };
 use rfd::AsyncFileDialog;
-use tokio::task::JoinHandle;
 
 pub struct State {
     pub web_origin: Origin,
     pub rpc_server: RpcServer,
-    pub tonic_qr: qr_code::Data,
-    pub axum_qr: qr_code::Data,
-    pub working_process: Option<JoinHandle<()>>,
+    /// none without a certificate, there is nothing to pin
+    pub tonic_qr: Option<qr_code::Data>,
+    pub axum_qr: Option<qr_code::Data>,
+    pub working_process: Option<Handle>,
+    /// what went wrong while serving, cleared on the next launch
+    pub problems: Vec<String>,
+    /// why the certificate of this install could not be used, stays until restarted
+    certificate_problem: Option<String>,
+    /// as of the last tick while serving
+    pub transfers: Vec<TransferInfo>,
+    ticking: Option<Handle>,
+    /// KB/s as typed, applied to running transfers whenever they parse
+    pub global_limit: String,
+    pub connection_limit: String,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Launch,
     Stop,
-    PickTarget,
-    TargetPicked(Option<PathBuf>),
+    Problem(String),
+    Served(Result<(), String>),
+    AddShare,
+    SharePicked(Option<PathBuf>),
+    RemoveShare(String),
+    PickMode(ShareMode),
+    GlobalLimit(String),
+    ConnectionLimit(String),
+    Tick,
+    CancelTransfer(u64),
 }
 
 impl From<Message> for crate::Message {
@@ -45,19 +79,40 @@ impl From<Message> for crate::Message {
 
 impl State {
     pub fn new(local_ip: IpAddr, tonic_port: u16, axum_port: u16) -> Self {
+        let rpc_server = RpcServer::new(Shares::default().with(home_dir().unwrap()), tonic_port);
+        // a certificate made for this run alone would be refused by every paired device
+        let (rpc_server, certificate_problem) = match Identity::load_or_create(&config_dir()) {
+            Ok(identity) => (rpc_server.identity(identity), None),
+            Err(err) => {
+                let problem = format!(
+                    "can not serve without the certificate kept in {}: {err}",
+                    config_dir().display()
+                );
+                (rpc_server, Some(problem))
+            }
+        };
+        // the fingerprint rides along so scanners can pin the certificate
+        let fingerprint = rpc_server.identity.as_ref().map(|x| &x.fingerprint);
         let mut origin = Origin::new(local_ip, tonic_port);
-        let tonic_url = qr_code::Data::new(origin.to_string()).unwrap();
+        let tonic_qr =
+            fingerprint.map(|fp| qr_code::Data::new(format!("{origin}/#fp={fp}")).unwrap());
         origin.port = axum_port;
-        let axum_url = qr_code::Data::new(origin.to_string()).unwrap();
+        // and the token so browsers pair on the first visit
+        let axum_qr = fingerprint.map(|fp| {
+            qr_code::Data::new(format!("{origin}/?token={}#fp={fp}", rpc_server.token)).unwrap()
+        });
         Self {
             web_origin: origin,
-            tonic_qr: tonic_url,
-            axum_qr: axum_url,
+            tonic_qr,
+            axum_qr,
             working_process: None,
-            rpc_server: RpcServer {
-                target_dir: home_dir().unwrap(),
-                port: tonic_port,
-            },
+            problems: Vec::new(),
+            certificate_problem,
+            transfers: Vec::new(),
+            ticking: None,
+            global_limit: String::new(),
+            connection_limit: String::new(),
+            rpc_server,
         }
     }
 }
@@ -66,10 +121,14 @@ impl State {
     pub fn view<'a>(&'a self) -> Element<'a, crate::Message> {
         let home = go_home_button();
         let serve = self.serve_button();
-        let tp = self.target_pick();
+        let tp = self.shares_section();
+        let mp = self.mode_pick();
+        let lm = self.limits_section();
+        let ts = self.transfers_section();
         let us = self.url_section();
+        let ps = self.problems_section();
 
-        let col = widget::column![home, serve, tp, us]
+        let col = widget::column![home, serve, ps, tp, mp, lm, ts, us]
             .spacing(30)
             .padding(20)
             .align_x(Center);
@@ -113,37 +172,54 @@ impl State {
                 ..Default::default()
             }
         })
-        .on_press({
-            match &self.working_process {
-                Some(_) => Message::Stop,
-                None => Message::Launch,
-            }
-            .into()
+        .on_press_maybe(match &self.working_process {
+            Some(_) => Some(Message::Stop.into()),
+            None => (!self.rpc_server.shares.is_empty() && self.rpc_server.identity.is_some())
+                .then_some(Message::Launch.into()),
         })
     }
 
+    fn problems_section(&self) -> Column<'_, crate::Message> {
+        let problems = self
+            .certificate_problem
+            .iter()
+            .chain(&self.problems)
+            .map(|x| {
+                text::Text::new(x)
+                    .size(20)
+                    .style(|theme: &Theme| text::Style {
+                        color: Some(theme.palette().danger),
+                    })
+                    .into()
+            });
+        Column::from_iter(problems).spacing(5.).align_x(Center)
+    }
+
     fn is_working(&self) -> bool {
         self.working_process.is_some()
     }
 
-    fn target_pick(&self) -> Row<'_, crate::Message> {
-        let my_text = |x: String| text::Text::new(x).size(60).align_x(Center).center();
-        let target = my_text(
-            self.rpc_server
-                .target_dir
-                .clone()
-                .to_str()
-                .map(|x| x.to_string())
-                .unwrap_or_default(),
-        );
-        let or = my_text(String::from("or"));
-        let pick = self.pick_button();
-        row![target, or, pick].align_y(Center).spacing(20.)
+    fn shares_section(&self) -> Column<'_, crate::Message> {
+        let working = self.is_working();
+        let shares = self.rpc_server.shares.iter().map(|share| {
+            let name = text::Text::new(format!("{} : {}", share.name, share.path.display()))
+                .size(30)
+                .align_x(Center)
+                .center();
+            let remove = Button::new(text::Text::new("remove").size(20.)).on_press_maybe(
+                (!working).then_some(Message::RemoveShare(share.name.clone()).into()),
+            );
+            Element::from(row![name, remove].align_y(Center).spacing(20.))
+        });
+        Column::from_vec(shares.collect())
+            .push(self.pick_button())
+            .align_x(Center)
+            .spacing(10.)
     }
 
     fn pick_button(&self) -> Button<'_, crate::Message> {
         let working = self.is_working();
-        let pt = text::Text::new("pick other target")
+        let pt = text::Text::new("add a folder")
             .align_x(Center)
             .align_y(Center)
             .size(25.);
@@ -173,7 +249,113 @@ impl State {
                     ..Default::default()
                 }
             })
-            .on_press_maybe((!working).then_some(Message::PickTarget.into()))
+            .on_press_maybe((!working).then_some(Message::AddShare.into()))
+    }
+
+    fn mode_pick(&self) -> Row<'_, crate::Message> {
+        let working = self.is_working();
+        let current = self.rpc_server.mode;
+        let buttons = ShareMode::ALL.map(|mode| {
+            let label = text::Text::new(mode.label()).size(25.).align_x(Center);
+            Button::new(label)
+                .padding(10.)
+                .style(move |theme: &Theme, _| {
+                    let Palette {
+                        background,
+                        primary,
+                        text,
+                        ..
+                    } = theme.palette();
+                    let bg = if mode == current { primary } else { background };
+                    Style {
+                        background: Some(Background::Color(bg)),
+                        text_color: text,
+                        border: Border {
+                            width: 2.,
+                            radius: Radius::new(20.),
+                            color: primary,
+                        },
+                        ..Default::default()
+                    }
+                })
+                .on_press_maybe((!working).then_some(Message::PickMode(mode).into()))
+        });
+        Row::from_vec(buttons.map(Element::from).into()).spacing(10.)
+    }
+
+    /// unlike the shares and mode these change while serving
+    fn limits_section(&self) -> Row<'_, crate::Message> {
+        let input = |label: &'static str, value: &str, msg: fn(String) -> Message| {
+            let valid = parse_rate(value).is_some();
+            let input = TextInput::new("no limit", value)
+                .size(25.)
+                .padding(10.)
+                .width(160.)
+                .style(move |theme, _| {
+                    if valid {
+                        style_input(theme)
+                    } else {
+                        text_input::Style {
+                            value: theme.palette().danger,
+                            ..style_input(theme)
+                        }
+                    }
+                })
+                .on_input(move |x| msg(x).into());
+            row![text::Text::new(label).size(25.), input]
+                .spacing(10.)
+                .align_y(Center)
+        };
+        row![
+            input("total KB/s", &self.global_limit, Message::GlobalLimit),
+            input(
+                "per connection KB/s",
+                &self.connection_limit,
+                Message::ConnectionLimit
+            ),
+        ]
+        .spacing(30.)
+        .align_y(Center)
+    }
+
+    fn transfers_section(&self) -> Option<Column<'_, crate::Message>> {
+        if self.transfers.is_empty() {
+            return None;
+        }
+        let transfers = self.transfers.iter().map(|transfer| {
+            let TransferInfo {
+                id,
+                direction,
+                peer,
+                path,
+                bytes,
+                total,
+                started,
+                rate,
+            } = transfer;
+            let verb = match direction {
+                Direction::Download => "sending",
+                Direction::Upload => "receiving",
+            };
+            let peer = peer.map(|x| x.to_string()).unwrap_or_default();
+            let title = text::Text::new(format!("{verb} {} {peer}", path.display())).size(20.);
+            let done = match total {
+                Some(total) => format!("{} of {}", format_size(*bytes), format_size(*total)),
+                None => format_size(*bytes),
+            };
+            let detail = text::Text::new(format!(
+                "{done} at {}/s for {} seconds",
+                format_size(*rate),
+                started.elapsed().as_secs()
+            ))
+            .size(15.);
+            let bar = total.map(|total| progress_bar(0.0..=(total as f32), *bytes as f32));
+            let cancel = Button::new(text::Text::new("cancel").size(20.))
+                .on_press(Message::CancelTransfer(*id).into());
+            let left = widget::column![title, bar, detail].spacing(3.).width(500.);
+            Element::from(row![left, cancel].spacing(10.).align_y(Center))
+        });
+        Some(Column::from_vec(transfers.collect()).spacing(10.))
     }
 
     fn url_section(&self) -> Column<'_, crate::Message> {
@@ -195,14 +377,62 @@ impl State {
             self.web_origin.port,
             "web app : ",
         ));
-        let native_qr = Container::new(qr_code(&self.tonic_qr).cell_size(13));
-        let web_qr = Container::new(qr_code(&self.axum_qr).cell_size(13));
-        let web = column![native_url, native_qr].spacing(5.);
+        let token = my_text(format!("pairing token : {}", self.rpc_server.token));
+        let native_qr = self
+            .tonic_qr
+            .as_ref()
+            .map(|x| Container::new(qr_code(x).cell_size(13)));
+        let web_qr = self
+            .axum_qr
+            .as_ref()
+            .map(|x| Container::new(qr_code(x).cell_size(13)));
+        let web = column![native_url, native_qr, token].spacing(5.);
         let native = column![web_url, web_qr].spacing(5.);
         let row = row![web, native].spacing(15.);
-        widget::column![at, row]
+        let fingerprint = self.rpc_server.identity.as_ref().map(|identity| {
+            text::Text::new(format!(
+                "certificate fingerprint : {}",
+                identity.fingerprint
+            ))
+            .size(20)
+            .align_x(Center)
+        });
+        widget::column![at, row, fingerprint]
     }
 }
+impl State {
+    fn stop(&mut self) {
+        if let Some(x) = self.working_process.take() {
+            x.abort();
+        }
+        if let Some(ticking) = self.ticking.take() {
+            ticking.abort();
+        }
+        // transfers already running would go on without a server to list them
+        self.rpc_server.transfers.cancel_all();
+        self.transfers.clear();
+    }
+}
+
+/// bytes per second from KB/s as typed, empty means unlimited
+fn parse_rate(typed: &str) -> Option<u64> {
+    let typed = typed.trim();
+    if typed.is_empty() {
+        return Some(0);
+    }
+    typed.parse::<u64>().ok().map(|x| x.saturating_mul(1024))
+}
+
+/// how often the transfers shown are refreshed
+const TICK: Duration = Duration::from_millis(500);
+
+fn ticks() -> impl stream::Stream<Item = ()> {
+    stream::unfold((), |_| async {
+        tokio::time::sleep(TICK).await;
+        Some(((), ()))
+    })
+}
+
 fn address_msg(local_ip: &IpAddr, port: u16, prefix: &str) -> String {
     format!("{prefix} {}", Origin::new(*local_ip, port))
 }
@@ -214,13 +444,51 @@ pub async fn which_target() -> Option<PathBuf> {
         .map(|x| x.path().to_path_buf())
 }
 
-pub async fn serve(target_path: PathBuf, tonic_port: u16, axum_port: u16) {
-    let one = server::Server::new(target_path.clone())
-        .port(axum_port)
-        .serve();
-    let two = RpcServer::new(target_path.clone(), tonic_port);
-    let two = two.serve();
-    let (_, _) = tokio::join!(one, two);
+/// runs both servers and the announcement, sending what goes wrong on the way
+fn serve(rpc_server: RpcServer, axum_port: u16) -> impl Straw<(), String, String> {
+    sipper(async move |mut sender| {
+        let Some(identity) = rpc_server.identity.clone() else {
+            return Err(String::from("there is no certificate to serve with"));
+        };
+        let announcement = Announcement {
+            name: rpc_server.name.clone(),
+            shares: rpc_server.shares.iter().map(|x| x.name.clone()).collect(),
+            rpc_port: rpc_server.port,
+            web_port: axum_port,
+        };
+        // serving goes on even when another server on this machine holds the discovery port
+        let announce = async {
+            if let Err(err) = discovery::announce(announcement, discovery::PORT).await {
+                sender
+                    .send(format!("other devices will not find this server: {err}"))
+                    .await;
+            }
+        };
+        let one = server::Server::new(rpc_server.shares.clone())
+            .port(axum_port)
+            .identity(identity)
+            .mode(rpc_server.mode)
+            .limits(rpc_server.limits.clone())
+            .transfers(rpc_server.transfers.clone())
+            .token(&rpc_server.token);
+        let one = async {
+            one.serve()
+                .await
+                .map_err(|err| format!("the web server stopped: {err}"))
+        };
+        let two = async {
+            rpc_server
+                .serve()
+                .await
+                .map_err(|err| format!("the app server stopped: {err}"))
+        };
+        // the first server to fail takes the rest down with it
+        tokio::try_join!(one, two, async {
+            announce.await;
+            Ok(())
+        })?;
+        Ok(())
+    })
 }
 
 impl crate::State {
@@ -228,27 +496,68 @@ impl crate::State {
         let state = &mut self.server;
         match msg {
             Message::Launch => {
-                state.working_process = Some(tokio::spawn(serve(
-                    state.rpc_server.target_dir.clone(),
-                    state.rpc_server.port,
-                    state.web_origin.port,
-                )));
-                Task::none()
+                state.problems.clear();
+                let (serving, handle) = Task::sip(
+                    serve(state.rpc_server.clone(), state.web_origin.port),
+                    |x| Message::Problem(x).into(),
+                    |x| Message::Served(x).into(),
+                )
+                .abortable();
+                state.working_process = Some(handle);
+                let (ticking, handle) = Task::run(ticks(), |_| Message::Tick.into()).abortable();
+                state.ticking = Some(handle);
+                Task::batch([serving, ticking])
             }
             Message::Stop => {
-                if let Some(x) = &state.working_process {
-                    x.abort();
-                    state.working_process = None;
+                state.stop();
+                Task::none()
+            }
+            Message::Problem(problem) => {
+                state.problems.push(problem);
+                Task::none()
+            }
+            Message::Served(result) => {
+                state.stop();
+                if let Err(err) = result {
+                    state.problems.push(err);
                 }
                 Task::none()
             }
-            Message::PickTarget => {
-                Task::perform(which_target(), |x| Message::TargetPicked(x).into())
+            Message::Tick => {
+                state.transfers = state.rpc_server.transfers.list();
+                Task::none()
+            }
+            Message::CancelTransfer(id) => {
+                state.rpc_server.transfers.cancel(id);
+                Task::none()
             }
-            Message::TargetPicked(path_buf) => {
+            Message::AddShare => Task::perform(which_target(), |x| Message::SharePicked(x).into()),
+            Message::SharePicked(path_buf) => {
                 if let Some(path_buf) = path_buf {
-                    state.rpc_server.target_dir = path_buf;
+                    state.rpc_server.shares.add(path_buf);
+                }
+                Task::none()
+            }
+            Message::RemoveShare(name) => {
+                state.rpc_server.shares.remove(&name);
+                Task::none()
+            }
+            Message::PickMode(mode) => {
+                state.rpc_server.mode = mode;
+                Task::none()
+            }
+            Message::GlobalLimit(typed) => {
+                if let Some(rate) = parse_rate(&typed) {
+                    state.rpc_server.limits.set_global(rate);
+                }
+                state.global_limit = typed;
+                Task::none()
+            }
+            Message::ConnectionLimit(typed) => {
+                if let Some(rate) = parse_rate(&typed) {
+                    state.rpc_server.limits.set_per_connection(rate);
                 }
+                state.connection_limit = typed;
                 Task::none()
             }
         }
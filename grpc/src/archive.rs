@@ -0,0 +1,311 @@
+use crate::{compression::is_precompressed, nav::ArchiveFormat, shares::Shares};
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use zip::{
+    CompressionMethod, ZipWriter,
+    write::{SimpleFileOptions, StreamWriter},
+};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+impl ArchiveFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "tar" => Some(Self::Tar),
+            "zip" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::Zip => "zip",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Tar => "application/x-tar",
+            Self::Zip => "application/zip",
+        }
+    }
+}
+
+/// packs `paths` into one archive while it is being sent, nothing touches the disk.
+///
+/// `paths` must already be confined to the `shares`, links found on the way are only
+/// followed while they stay inside one of them. entries are named as clients see them,
+/// from the folder holding every path on. a failure ends the stream with the error and
+/// nothing after it, so an http download is cut short rather than ending cleanly.
+pub fn stream(
+    shares: Shares,
+    paths: Vec<PathBuf>,
+    format: ArchiveFormat,
+) -> ReceiverStream<io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let writer = ChunkSender {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            aborted: Rc::default(),
+        };
+        let result = entries(&shares, &paths).and_then(|entries| match format {
+            ArchiveFormat::Tar => write_tar(writer, &entries),
+            ArchiveFormat::Zip => write_zip(writer, &entries),
+        });
+        // a broken pipe only means the receiver is gone
+        if let Err(err) = result
+            && err.kind() != io::ErrorKind::BrokenPipe
+        {
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+struct Entry {
+    path: PathBuf,
+    name: PathBuf,
+    is_dir: bool,
+    size: u64,
+}
+
+fn entries(shares: &Shares, paths: &[PathBuf]) -> io::Result<Vec<Entry>> {
+    let roots = shares.roots();
+    let inside = |x: PathBuf| roots.iter().any(|root| x.starts_with(root));
+    let shown = paths
+        .iter()
+        .map(|path| {
+            shares
+                .iter()
+                .find_map(|x| x.relative(path))
+                .unwrap_or_else(|| PathBuf::from(path.file_name().unwrap_or_default()))
+        })
+        .collect::<Vec<_>>();
+    let base = common_parent(&shown);
+    let mut entries = Vec::new();
+    for (root, shown) in paths.iter().zip(&shown) {
+        let top = shown.strip_prefix(&base).unwrap_or(shown);
+        let mut pending = vec![root.clone()];
+        while let Some(path) = pending.pop() {
+            // only what was asked for fails the archive, like a walk it passes over
+            // what can not be read further down
+            let is_root = path == *root;
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) if !is_root => continue,
+                Err(err) => return Err(err),
+            };
+            let symlink = metadata.is_symlink();
+            if symlink && !path.canonicalize().is_ok_and(inside) {
+                continue;
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) if !is_root => continue,
+                Err(err) => return Err(err),
+            };
+            // linked folders are stored but never entered, they may loop
+            if metadata.is_dir() && !symlink {
+                match fs::read_dir(&path) {
+                    Ok(dir) => pending.extend(dir.filter_map(|x| x.ok()).map(|x| x.path())),
+                    Err(_) if !is_root => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            let name = match path.strip_prefix(root) {
+                Ok(rest) if !rest.as_os_str().is_empty() => top.join(rest),
+                _ => top.to_path_buf(),
+            };
+            entries.push(Entry {
+                path,
+                name,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// the folder every `shown` path is in, the top level when they share none
+fn common_parent(shown: &[PathBuf]) -> PathBuf {
+    let mut parents = shown.iter().map(|x| x.parent().unwrap_or(Path::new("")));
+    let Some(first) = parents.next() else {
+        return PathBuf::new();
+    };
+    parents.fold(first.to_path_buf(), |common, parent| {
+        common
+            .components()
+            .zip(parent.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect()
+    })
+}
+
+fn write_tar(writer: ChunkSender, entries: &[Entry]) -> io::Result<()> {
+    let aborted = writer.aborted.clone();
+    let mut builder = tar::Builder::new(writer);
+    for Entry {
+        path, name, is_dir, ..
+    } in entries
+    {
+        let appended = if *is_dir {
+            builder.append_dir(name, path)
+        } else {
+            builder.append_path_with_name(path, name)
+        };
+        appended.inspect_err(|_| aborted.set(true))?;
+    }
+    builder.into_inner()?.flush()
+}
+
+fn write_zip(writer: ChunkSender, entries: &[Entry]) -> io::Result<()> {
+    let aborted = writer.aborted.clone();
+    let mut zip = ZipWriter::new_stream(writer);
+    for entry in entries {
+        add_to_zip(&mut zip, entry).inspect_err(|_| aborted.set(true))?;
+    }
+    zip.finish()?.into_inner().flush()
+}
+
+fn add_to_zip(zip: &mut ZipWriter<StreamWriter<ChunkSender>>, entry: &Entry) -> io::Result<()> {
+    let Entry {
+        path,
+        name,
+        is_dir,
+        size,
+    } = entry;
+    let name = name
+        .iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if *is_dir {
+        zip.add_directory(name, SimpleFileOptions::default())?;
+        return Ok(());
+    }
+    let method = if is_precompressed(path) {
+        CompressionMethod::Stored
+    } else {
+        CompressionMethod::Deflated
+    };
+    let options = SimpleFileOptions::default()
+        .compression_method(method)
+        .large_file(*size >= u32::MAX as u64);
+    zip.start_file(name, options)?;
+    io::copy(&mut File::open(path)?, zip)?;
+    Ok(())
+}
+
+/// hands the archive over to the async side in full chunks
+struct ChunkSender {
+    tx: mpsc::Sender<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    /// set when an entry failed, archives finish themselves when dropped and that
+    /// ending must not make a partial archive look whole
+    aborted: Rc<Cell<bool>>,
+}
+
+impl ChunkSender {
+    fn send(&mut self) -> io::Result<()> {
+        if self.aborted.get() {
+            return Err(io::Error::other("the archive was aborted"));
+        }
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver is gone"))
+    }
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use tokio_stream::StreamExt;
+
+    fn base(name: &str) -> PathBuf {
+        let base = temp_dir().join(format!("ours-archive-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        base
+    }
+
+    /// the names in a tar and whether the stream ended in an error
+    async fn pack(shares: Shares, paths: Vec<PathBuf>) -> (Vec<String>, bool) {
+        let chunks = stream(shares, paths, ArchiveFormat::Tar)
+            .collect::<Vec<_>>()
+            .await;
+        let failed = chunks.last().is_some_and(|x| x.is_err());
+        let data = chunks.into_iter().flatten().flatten().collect::<Vec<_>>();
+        let mut names = Vec::new();
+        if !failed {
+            let mut archive = tar::Archive::new(data.as_slice());
+            for entry in archive.entries().unwrap() {
+                let path = entry.unwrap().path().unwrap().into_owned();
+                names.push(path.to_string_lossy().trim_end_matches('/').to_string());
+            }
+            names.sort();
+        }
+        (names, failed)
+    }
+
+    #[tokio::test]
+    async fn shares_keep_their_names() {
+        let base = base("names");
+        fs::create_dir_all(base.join("one/x")).unwrap();
+        fs::create_dir_all(base.join("two/x")).unwrap();
+        fs::write(base.join("one/x/a.txt"), b"a").unwrap();
+        fs::write(base.join("two/x/a.txt"), b"b").unwrap();
+        let shares = Shares::default()
+            .with(base.join("one/x"))
+            .with(base.join("two/x"));
+        let (names, failed) = pack(shares.clone(), shares.roots()).await;
+        assert!(!failed);
+        assert_eq!(names, ["x", "x (2)", "x (2)/a.txt", "x/a.txt"]);
+        // inside a share names start at the folder asked for
+        let (names, _) = pack(shares, vec![base.join("two/x/a.txt")]).await;
+        assert_eq!(names, ["a.txt"]);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_failed_entry_ends_the_stream_in_an_error() {
+        let base = base("failed");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("a.txt"), vec![7; 3 * CHUNK_SIZE]).unwrap();
+        // sockets can not be opened like files
+        let _socket = std::os::unix::net::UnixListener::bind(base.join("b.sock")).unwrap();
+        let shares = Shares::default().with(base.clone());
+        let (_, failed) = pack(shares, vec![base.clone()]).await;
+        assert!(failed);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use tonic::{Request, Status, metadata::AsciiMetadataValue, service::Interceptor};
+
+pub const TOKEN_HEADER: &str = "x-ours-token";
+
+/// no 0/O or 1/I so the token survives being read aloud or typed from a screen
+const ALPHABET: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+const TOKEN_LEN: usize = 8;
+
+/// a fresh pairing token shown as "XXXX-XXXX"
+pub fn pairing_token() -> String {
+    let mut bytes = [0; TOKEN_LEN];
+    getrandom::fill(&mut bytes).expect("no random source for the pairing token");
+    let token = bytes
+        .iter()
+        .map(|x| ALPHABET[*x as usize % ALPHABET.len()] as char)
+        .collect::<String>();
+    format!("{}-{}", &token[..TOKEN_LEN / 2], &token[TOKEN_LEN / 2..])
+}
+
+/// what gets compared, users may type the token in any case with or without the dash
+pub fn normalize(token: &str) -> String {
+    token
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_uppercase())
+        .collect()
+}
+
+/// rejects every call that does not carry the pairing token
+#[derive(Clone)]
+pub struct Pairing {
+    token: Arc<str>,
+}
+
+impl Pairing {
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: normalize(token).into(),
+        }
+    }
+
+    /// compared in full so the time taken says nothing about the token
+    pub fn accepts(&self, given: &str) -> bool {
+        let given = normalize(given);
+        !self.token.is_empty()
+            && given.len() == self.token.len()
+            && given
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl Interceptor for Pairing {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        let given = req
+            .metadata()
+            .get(TOKEN_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        if self.accepts(given) {
+            Ok(req)
+        } else {
+            Err(Status::unauthenticated("missing or wrong pairing token"))
+        }
+    }
+}
+
+/// attaches the pairing token to every call of the client
+#[derive(Clone)]
+pub struct Attach {
+    token: AsciiMetadataValue,
+}
+
+impl Attach {
+    pub fn new(token: &str) -> Result<Self, Status> {
+        let token = normalize(token)
+            .parse()
+            .map_err(|_| Status::invalid_argument("the pairing token is not valid"))?;
+        Ok(Self { token })
+    }
+}
+
+impl Interceptor for Attach {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        req.metadata_mut().insert(TOKEN_HEADER, self.token.clone());
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_two_readable_halves() {
+        let token = pairing_token();
+        let (one, two) = token.split_once('-').unwrap();
+        assert_eq!((one.len(), two.len()), (TOKEN_LEN / 2, TOKEN_LEN / 2));
+        assert!(normalize(&token).bytes().all(|x| ALPHABET.contains(&x)));
+    }
+
+    #[test]
+    fn case_and_dashes_do_not_matter() {
+        assert_eq!(normalize("abcd-efgh"), "ABCDEFGH");
+        assert_eq!(normalize(" AbCd EfGh "), "ABCDEFGH");
+        let pairing = Pairing::new("ABCD-EFGH");
+        for given in ["ABCD-EFGH", "abcdefgh", "abcd-EFGH", "ab-cd-ef-gh"] {
+            assert!(pairing.accepts(given), "{given}");
+        }
+    }
+
+    #[test]
+    fn wrong_tokens_are_refused() {
+        let pairing = Pairing::new("ABCD-EFGH");
+        for given in ["ABCD-EFGX", "ABCD-EFG", "ABCD-EFGHI", "", "-"] {
+            assert!(!pairing.accepts(given), "{given}");
+        }
+    }
+
+    #[test]
+    fn an_empty_token_pairs_nobody() {
+        let pairing = Pairing::new("-");
+        assert!(!pairing.accepts(""));
+        assert!(!pairing.accepts("-"));
+    }
+
+    #[test]
+    fn calls_need_the_attached_token() {
+        let mut pairing = Pairing::new("ABCD-EFGH");
+        let status = pairing.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let attached = Attach::new("abcd-efgh").unwrap().call(Request::new(()));
+        assert!(pairing.call(attached.unwrap()).is_ok());
+        let attached = Attach::new("ABCD-EFGX").unwrap().call(Request::new(()));
+        let status = pairing.call(attached.unwrap()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
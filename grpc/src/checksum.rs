@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub(crate) type Digest = [u8; blake3::OUT_LEN];
+
+/// blake3 digest of the whole file, hashed off the async runtime
+pub(crate) async fn hash_file(path: PathBuf) -> io::Result<Digest> {
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(File::open(path)?)?;
+        Ok(*hasher.finalize().as_bytes())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+#[derive(Debug, Clone)]
+struct Cached {
+    modified: SystemTime,
+    size: u64,
+    digest: Digest,
+}
+
+/// digests stay valid until the file's modification time or size changes
+#[derive(Default, Debug, Clone)]
+pub(crate) struct ChecksumCache(Arc<Mutex<HashMap<PathBuf, Cached>>>);
+
+impl ChecksumCache {
+    pub(crate) async fn get(&self, path: PathBuf) -> io::Result<Digest> {
+        let metadata = tokio::fs::metadata(&path).await?;
+        let modified = metadata.modified()?;
+        let size = metadata.len();
+        if let Some(cached) = self.0.lock().unwrap().get(&path)
+            && cached.modified == modified
+            && cached.size == size
+        {
+            return Ok(cached.digest);
+        }
+        let digest = hash_file(path.clone()).await?;
+        self.0.lock().unwrap().insert(
+            path,
+            Cached {
+                modified,
+                size,
+                digest,
+            },
+        );
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ours-checksum-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// marks what the cache holds for `path`, so a hit hands the mark back
+    fn mark(cache: &ChecksumCache, path: &PathBuf) -> Digest {
+        let marked = [7; blake3::OUT_LEN];
+        cache.0.lock().unwrap().get_mut(path).unwrap().digest = marked;
+        marked
+    }
+
+    #[tokio::test]
+    async fn unchanged_files_are_not_hashed_again() {
+        let path = temp_file("unchanged", b"data");
+        let cache = ChecksumCache::default();
+        let digest = cache.get(path.clone()).await.unwrap();
+        assert_eq!(digest, *blake3::hash(b"data").as_bytes());
+        let marked = mark(&cache, &path);
+        assert_eq!(cache.get(path.clone()).await.unwrap(), marked);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rewritten_files_are_hashed_again() {
+        let path = temp_file("rewritten", b"data");
+        let cache = ChecksumCache::default();
+        cache.get(path.clone()).await.unwrap();
+
+        mark(&cache, &path);
+        std::fs::write(&path, b"longer data").unwrap();
+        let digest = cache.get(path.clone()).await.unwrap();
+        assert_eq!(digest, *blake3::hash(b"longer data").as_bytes());
+
+        // same size, only the modification time tells
+        mark(&cache, &path);
+        std::fs::write(&path, b"other data!").unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let digest = cache.get(path.clone()).await.unwrap();
+        assert_eq!(digest, *blake3::hash(b"other data!").as_bytes());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
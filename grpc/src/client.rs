@@ -1,38 +1,89 @@
 use crate::{
+    ArchiveFormat, Compression, ConflictPolicy, auth, checksum,
+    compression::{CHUNK_SIZE, negotiate},
     error::RpcError,
+    info::ServerInfo,
+    media::MediaInfo,
     nav::{
-        DownloadRequest, FileSizeRequest, LsRequest, ResumeDownloadRequest, UploadMetadata,
-        UploadRequest, nav_service_client::NavServiceClient, upload_request::Data,
+        ArchiveRequest, ChecksumRequest, DeleteRequest, DownloadRequest, FileSizeRequest,
+        LsRequest, MediaInfoRequest, MkdirRequest, PasteRequest, ReadRangeRequest, RenameRequest,
+        ResumeDownloadRequest, ServerInfoRequest, ThumbnailRequest, UploadMetadata, UploadRequest,
+        UploadStatusRequest, WalkRequest, WatchRequest, nav_service_client::NavServiceClient,
+        upload_request::Data,
     },
-    top,
+    search::Query,
+    tls, top,
+    watch::Change,
 };
 use std::{
+    io::{self, SeekFrom},
     net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
 };
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, BufReader},
+    io::{AsyncReadExt, AsyncSeekExt, BufReader},
     sync::{Mutex, mpsc},
 };
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Streaming, transport::Channel};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
+use tonic::{Streaming, service::interceptor::InterceptedService, transport::Channel};
 
-pub use crate::nav::{DownloadResponse, ResumeDownloadResponse};
+pub use crate::nav::{
+    ArchiveResponse, DownloadResponse, ReadRangeResponse, ResumeDownloadResponse, UploadResponse,
+};
+
+pub type NavClient = NavServiceClient<InterceptedService<Channel, auth::Attach>>;
 
 #[derive(Clone, Debug)]
 pub struct RpcClient {
     pub addr: SocketAddr,
-    pub client: Arc<Mutex<NavServiceClient<Channel>>>,
+    pub client: Arc<Mutex<NavClient>>,
+    pub compression: Compression,
+    /// of the server certificate this client trusts, see [`tls::pinned_channel`]
+    pub fingerprint: String,
 }
 
 impl RpcClient {
-    pub async fn new(addr: SocketAddr) -> Result<Self, RpcError> {
-        let client: NavServiceClient<Channel> =
-            NavServiceClient::connect(format!("http://{}", addr)).await?;
+    /// `token` is the pairing token shown on the server's screen,
+    /// `pinned` the certificate fingerprint trusted for this server if any
+    pub async fn new(
+        addr: SocketAddr,
+        token: &str,
+        pinned: Option<String>,
+    ) -> Result<Self, RpcError> {
+        let (channel, fingerprint) = tls::pinned_channel(addr, pinned).await?;
+        let client = NavServiceClient::with_interceptor(channel, auth::Attach::new(token)?);
         let client = Arc::new(Mutex::new(client));
-        Ok(Self { addr, client })
+        Ok(Self {
+            addr,
+            client,
+            compression: Compression::Zstd,
+            fingerprint,
+        })
+    }
+
+    /// encoding asked for on transfers, the server still skips it for media
+    pub fn compression(self, compression: Compression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// servers older than the info call speak no protocol this client knows
+    pub async fn server_info(self) -> Result<ServerInfo, RpcError> {
+        let mut client = self.client.lock().await;
+        match client.server_info(ServerInfoRequest {}).await {
+            Ok(info) => Ok(info.into_inner().into()),
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                Err(RpcError::Incompatible {
+                    server: 0,
+                    client: crate::info::PROTOCOL,
+                })
+            }
+            Err(status) => Err(status.into()),
+        }
     }
 
     pub async fn ls(self, target: PathBuf) -> Result<Vec<top::Unit>, RpcError> {
@@ -46,24 +97,101 @@ impl RpcClient {
             .into_inner()
             .units
             .into_iter()
-            .map(|x| {
-                let Ok(path) = x.path.parse::<PathBuf>();
-                top::Unit {
-                    path,
-                    kind: x.kind(),
-                }
-            })
+            .map(top::Unit::from)
             .collect();
         units.sort_by_key(|x| (x.kind, x.name()));
         Ok(units)
     }
 
+    /// lists `target` in batches as the server reads the directory
+    pub async fn ls_stream(
+        self,
+        target: PathBuf,
+    ) -> Result<impl Stream<Item = Result<Vec<top::Unit>, RpcError>> + Send + Unpin, RpcError> {
+        let req = LsRequest {
+            path: target.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        let stream = client.ls_stream(req).await?.into_inner();
+        Ok(stream.map(|batch| {
+            let units = batch?.units.into_iter().map(top::Unit::from).collect();
+            Ok(units)
+        }))
+    }
+
+    /// streams every descendant of `target`, folders included
+    pub async fn walk(
+        self,
+        target: PathBuf,
+        max_depth: Option<u32>,
+        excludes: Vec<String>,
+    ) -> Result<impl Stream<Item = Result<Vec<top::Unit>, RpcError>> + Send + Unpin, RpcError> {
+        let req = WalkRequest {
+            path: target.to_str().unwrap().to_string(),
+            max_depth,
+            excludes,
+        };
+        let mut client = self.client.lock().await;
+        let stream = client.walk(req).await?.into_inner();
+        Ok(stream.map(|batch| {
+            let units = batch?.units.into_iter().map(top::Unit::from).collect();
+            Ok(units)
+        }))
+    }
+
+    /// streams what matches `query` anywhere below `target`, the top level searches every share
+    pub async fn search(
+        self,
+        target: PathBuf,
+        query: Query,
+    ) -> Result<impl Stream<Item = Result<Vec<top::Unit>, RpcError>> + Send + Unpin, RpcError> {
+        let req = query.request(&target);
+        let mut client = self.client.lock().await;
+        let stream = client.search(req).await?.into_inner();
+        Ok(stream.map(|batch| {
+            let units = batch?.units.into_iter().map(top::Unit::from).collect();
+            Ok(units)
+        }))
+    }
+
+    /// streams the changes to what `target` holds until the stream is dropped
+    pub async fn watch(
+        self,
+        target: PathBuf,
+    ) -> Result<impl Stream<Item = Result<Change, RpcError>> + Send + Unpin, RpcError> {
+        let req = WatchRequest {
+            path: target.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        let stream = client.watch(req).await?.into_inner();
+        Ok(stream.map(|change| Ok(Change::try_from(change?)?)))
+    }
+
+    /// compares the local copy at `local` with the server's `target`
+    pub async fn verify(self, target: &Path, local: &Path) -> Result<(), RpcError> {
+        let req = ChecksumRequest {
+            path: target.to_str().unwrap().to_string(),
+        };
+        let remote = {
+            let mut client = self.client.lock().await;
+            client.checksum(req).await?.into_inner().blake3
+        };
+        let local_digest = checksum::hash_file(local.to_path_buf()).await?;
+        if remote != local_digest {
+            return Err(RpcError::ChecksumMismatch(target.to_path_buf()));
+        }
+        Ok(())
+    }
+
     pub async fn download_stream(
         self,
         target: &Path,
     ) -> Result<(u64, Streaming<DownloadResponse>), RpcError> {
         let path = target.to_str().unwrap().to_string();
-        let req = DownloadRequest { path: path.clone() };
+        let req = DownloadRequest {
+            path: path.clone(),
+            compression: self.compression.into(),
+        };
         let mut client = self.client.lock().await;
         let stream = client.download(req).await?.into_inner();
         let req = FileSizeRequest { path };
@@ -80,67 +208,243 @@ impl RpcClient {
         let req = ResumeDownloadRequest {
             path: path.clone(),
             progress_index: progress_index as u64,
+            compression: self.compression.into(),
         };
         let mut client = self.client.lock().await;
         let stream = client.resume_download(req).await?.into_inner();
         Ok(stream)
     }
 
+    pub async fn range_stream(
+        self,
+        target: &Path,
+        offset: u64,
+        length: u64,
+    ) -> Result<Streaming<ReadRangeResponse>, RpcError> {
+        let req = ReadRangeRequest {
+            path: target.to_str().unwrap().to_string(),
+            offset,
+            length,
+            compression: self.compression.into(),
+        };
+        let mut client = self.client.lock().await;
+        let stream = client.read_range(req).await?.into_inner();
+        Ok(stream)
+    }
+
+    /// packs `targets` into one archive on the fly
+    pub async fn archive_stream(
+        self,
+        targets: &[PathBuf],
+        format: ArchiveFormat,
+    ) -> Result<Streaming<ArchiveResponse>, RpcError> {
+        let req = ArchiveRequest {
+            paths: paths_as_strings(targets),
+            format: format.into(),
+        };
+        let mut client = self.client.lock().await;
+        let stream = client.archive(req).await?.into_inner();
+        Ok(stream)
+    }
+
     pub async fn upload(
         self,
         location_path: PathBuf,
         target_path: PathBuf,
-    ) -> Result<(), RpcError> {
-        let tpath = target_path.to_str().unwrap().to_string();
-        let location_path = location_path.to_str().unwrap().to_string();
-        let init_req = UploadRequest {
+        conflict: ConflictPolicy,
+    ) -> Result<UploadResponse, RpcError> {
+        self.send_upload(location_path, target_path, 0, conflict)
+            .await
+    }
+
+    /// continues an interrupted upload from the bytes the server already kept
+    pub async fn resume_upload(
+        self,
+        location_path: PathBuf,
+        target_path: PathBuf,
+        conflict: ConflictPolicy,
+    ) -> Result<UploadResponse, RpcError> {
+        let offset = self
+            .clone()
+            .upload_status(&location_path, &target_path)
+            .await?;
+        self.send_upload(location_path, target_path, offset, conflict)
+            .await
+    }
+
+    /// bytes of `target_path` kept by the server from an interrupted upload
+    pub async fn upload_status(
+        self,
+        location_path: &Path,
+        target_path: &Path,
+    ) -> Result<u64, RpcError> {
+        let req = UploadStatusRequest {
+            target_path: upload_name(target_path),
+            location_path: location_path.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        let received = client.upload_status(req).await?.into_inner().received;
+        Ok(received)
+    }
+
+    async fn send_upload(
+        self,
+        location_path: PathBuf,
+        target_path: PathBuf,
+        offset: u64,
+        conflict: ConflictPolicy,
+    ) -> Result<UploadResponse, RpcError> {
+        let compression = negotiate(self.compression, &target_path);
+        let mut file = File::open(&target_path).await?;
+        let size = file.metadata().await?.len();
+        file.seek(SeekFrom::Start(offset)).await?;
+        let meta = UploadRequest {
             data: Some(Data::Meta(UploadMetadata {
-                target_path: tpath,
-                location_path,
+                target_path: upload_name(&target_path),
+                location_path: location_path.to_str().unwrap().to_string(),
+                compression: compression.into(),
+                offset,
+                conflict: conflict.into(),
+                size,
             })),
         };
-        let (tx, rx) = mpsc::channel::<UploadRequest>(100);
-        let mut client = self.client.lock().await;
-        let _ = client.upload(ReceiverStream::new(rx)).await?.into_inner();
-        let _ = tx.send(init_req).await;
-
-        let file = File::open(&target_path).await?;
-        let mut file = BufReader::new(file);
-
-        let mut buffer = bytes::BytesMut::with_capacity(1024 * 1024);
-        let res = loop {
-            let rb = match file.read_buf(&mut buffer).await {
-                Ok(rb) => rb,
-                Err(err) => {
-                    eprintln!(
-                        "ERROR : upload of {path} due to -> {err}",
-                        path = target_path.display()
-                    );
-                    match tx.send(UploadRequest { data: None }).await {
-                        Ok(_) => (),
-                        Err(err) => break Err(err),
-                    };
-                    break Ok(());
-                }
-            };
-            if rb == 0 {
-                break Ok(());
+
+        let (tx, rx) = mpsc::channel::<UploadRequest>(16);
+        let sender = tokio::spawn(async move {
+            if tx.send(meta).await.is_err() {
+                return Ok(());
             }
-            match tx
-                .send(UploadRequest {
-                    data: Some(Data::Chunk(buffer.to_vec())),
-                })
-                .await
-            {
-                Ok(_) => (),
-                Err(err) => break Err(err),
-            };
-            buffer.clear();
+            let result = send_chunks(file, compression, &tx).await;
+            if result.is_err() {
+                // an empty message tells the server to keep what it got
+                let _ = tx.send(UploadRequest { data: None }).await;
+            }
+            result
+        });
+        // a clone keeps the shared client free while the file is sent
+        let mut client = self.client.lock().await.clone();
+        let response = client.upload(ReceiverStream::new(rx)).await;
+        sender
+            .await
+            .map_err(|err| RpcError::from(err.to_string()))??;
+        Ok(response?.into_inner())
+    }
+
+    pub async fn mkdir(self, target: PathBuf) -> Result<(), RpcError> {
+        let req = MkdirRequest {
+            path: target.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        client.mkdir(req).await?;
+        Ok(())
+    }
+
+    /// a small jpeg preview of an image or video
+    pub async fn thumbnail(self, target: PathBuf) -> Result<Vec<u8>, RpcError> {
+        let req = ThumbnailRequest {
+            path: target.to_str().unwrap().to_string(),
         };
+        // a clone keeps the shared client free while ffmpeg renders
+        let mut client = self.client.lock().await.clone();
+        Ok(client.thumbnail(req).await?.into_inner().jpeg)
+    }
 
-        match res {
-            Ok(_) => Ok(()),
-            Err(err) => Err(RpcError::from(err.to_string())),
+    /// duration, codecs and size of a video or audio file
+    pub async fn media_info(self, target: PathBuf) -> Result<MediaInfo, RpcError> {
+        let req = MediaInfoRequest {
+            path: target.to_str().unwrap().to_string(),
+        };
+        // a clone keeps the shared client free while ffprobe runs
+        let mut client = self.client.lock().await.clone();
+        Ok(client.media_info(req).await?.into_inner().into())
+    }
+
+    /// gives `target` a new `name` inside the same folder
+    pub async fn rename(self, target: PathBuf, name: String) -> Result<(), RpcError> {
+        let req = RenameRequest {
+            path: target.to_str().unwrap().to_string(),
+            name,
+        };
+        let mut client = self.client.lock().await;
+        client.rename(req).await?;
+        Ok(())
+    }
+
+    /// folders that are not empty are only removed when `recursive`
+    pub async fn delete(self, targets: Vec<PathBuf>, recursive: bool) -> Result<(), RpcError> {
+        let req = DeleteRequest {
+            paths: paths_as_strings(&targets),
+            recursive,
+        };
+        let mut client = self.client.lock().await;
+        client.delete(req).await?;
+        Ok(())
+    }
+
+    pub async fn copy_to(
+        self,
+        targets: Vec<PathBuf>,
+        destination: PathBuf,
+    ) -> Result<(), RpcError> {
+        let req = PasteRequest {
+            paths: paths_as_strings(&targets),
+            destination: destination.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        client.copy(req).await?;
+        Ok(())
+    }
+
+    pub async fn move_to(
+        self,
+        targets: Vec<PathBuf>,
+        destination: PathBuf,
+    ) -> Result<(), RpcError> {
+        let req = PasteRequest {
+            paths: paths_as_strings(&targets),
+            destination: destination.to_str().unwrap().to_string(),
+        };
+        let mut client = self.client.lock().await;
+        client.r#move(req).await?;
+        Ok(())
+    }
+}
+
+fn paths_as_strings(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|x| x.to_str().unwrap().to_string())
+        .collect()
+}
+
+/// uploads go by file name, the server places them in the location
+fn upload_name(target_path: &Path) -> String {
+    target_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+async fn send_chunks(
+    file: File,
+    compression: Compression,
+    tx: &mpsc::Sender<UploadRequest>,
+) -> io::Result<()> {
+    let mut file = BufReader::new(file);
+    let mut buffer = bytes::BytesMut::with_capacity(CHUNK_SIZE);
+    loop {
+        buffer.clear();
+        if file.read_buf(&mut buffer).await? == 0 {
+            return Ok(());
+        }
+        // a closed channel means the server already answered, the answer says why
+        if tx
+            .send(UploadRequest::chunk(compression, &buffer)?)
+            .await
+            .is_err()
+        {
+            return Ok(());
         }
     }
 }
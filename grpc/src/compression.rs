@@ -0,0 +1,140 @@
+use crate::nav::{
+    Compression, DownloadResponse, ReadRangeResponse, ResumeDownloadResponse, UploadRequest,
+    upload_request::Data,
+};
+use common::{AUDIO_X, COMPRESSED_X, VIDEO_X};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// the most data one chunk carries once decompressed, both ways
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// already compressed formats gain nothing from another pass
+pub fn is_precompressed(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase())
+        .is_some_and(|x| {
+            VIDEO_X.contains(&x.as_str())
+                || AUDIO_X.contains(&x.as_str())
+                || COMPRESSED_X.contains(&x.as_str())
+        })
+}
+
+/// the encoding actually used for `path` when the peer asked for `requested`
+pub(crate) fn negotiate(requested: Compression, path: &Path) -> Compression {
+    if is_precompressed(path) {
+        Compression::None
+    } else {
+        requested
+    }
+}
+
+pub(crate) fn compress(encoding: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// refuses chunks that grow past [`CHUNK_SIZE`], a few bytes from a peer could fill the memory
+pub(crate) fn decompress(encoding: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        Compression::None => return Ok(data),
+        Compression::Zstd => Box::new(zstd::Decoder::new(data.as_slice())?),
+        Compression::Gzip => Box::new(GzDecoder::new(data.as_slice())),
+    };
+    let mut out = Vec::new();
+    decoder.take(CHUNK_SIZE as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "a chunk decompresses to more than it may hold",
+        ));
+    }
+    Ok(out)
+}
+
+macro_rules! impl_into_data {
+    ($($response:ident),*) => {
+        $(
+            impl $response {
+                /// the chunk as it is on disk, decompressed if the server compressed it
+                pub fn into_data(self) -> io::Result<Vec<u8>> {
+                    let encoding = self.compression();
+                    decompress(encoding, self.data)
+                }
+            }
+        )*
+    };
+}
+
+impl_into_data!(DownloadResponse, ResumeDownloadResponse, ReadRangeResponse);
+
+impl UploadRequest {
+    pub(crate) fn chunk(encoding: Compression, data: &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            data: Some(Data::Chunk(compress(encoding, data)?)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"ours ".repeat(10_000);
+        for encoding in [Compression::None, Compression::Zstd, Compression::Gzip] {
+            let packed = compress(encoding, &data).unwrap();
+            assert_eq!(decompress(encoding, packed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn full_chunk_passes() {
+        let data = vec![0; CHUNK_SIZE];
+        for encoding in [Compression::Zstd, Compression::Gzip] {
+            let packed = compress(encoding, &data).unwrap();
+            assert_eq!(decompress(encoding, packed).unwrap().len(), CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn bombs_are_refused() {
+        let data = vec![0; CHUNK_SIZE * 64];
+        for encoding in [Compression::Zstd, Compression::Gzip] {
+            let packed = compress(encoding, &data).unwrap();
+            assert!(packed.len() < CHUNK_SIZE);
+            let err = decompress(encoding, packed).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn precompressed_files_are_sent_as_they_are() {
+        assert_eq!(
+            negotiate(Compression::Zstd, Path::new("a/movie.MKV")),
+            Compression::None
+        );
+        assert_eq!(
+            negotiate(Compression::Gzip, Path::new("a/notes.txt")),
+            Compression::Gzip
+        );
+        assert_eq!(
+            negotiate(Compression::Zstd, Path::new("a/no_extension")),
+            Compression::Zstd
+        );
+    }
+}
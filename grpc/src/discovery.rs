@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// servers listen here for probes, clients send from any port
+pub const PORT: u16 = 47_631;
+const PROBE: &[u8] = b"ours?";
+const ANSWER: &[u8] = b"ours!";
+
+/// what a running server tells the clients looking for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub name: String,
+    pub shares: Vec<String>,
+    pub rpc_port: u16,
+    pub web_port: u16,
+}
+
+/// a server that answered, at the address it answered from
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub ip: IpAddr,
+    pub announcement: Announcement,
+}
+
+/// answers every probe sent to `port` until the future is dropped
+pub async fn announce(announcement: Announcement, port: u16) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    let answer = [ANSWER, &serde_json::to_vec(&announcement)?].concat();
+    let mut buf = [0; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // windows reports an earlier answer that could not be delivered here
+            Err(err) => {
+                eprintln!("can not receive a discovery probe: {err}");
+                continue;
+            }
+        };
+        if &buf[..len] == PROBE {
+            // a client that went away is no reason to stop answering the others
+            let _ = socket.send_to(&answer, from).await;
+        }
+    }
+}
+
+/// probes the network and this machine, collecting the servers that answer within `wait`
+pub async fn discover(port: u16, wait: Duration) -> io::Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    // the broadcast fails without a network, the loopback probe still finds servers here
+    let _ = socket
+        .send_to(PROBE, SocketAddr::from((Ipv4Addr::BROADCAST, port)))
+        .await;
+    socket
+        .send_to(PROBE, SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        .await?;
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<Found> = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        let Some(answer) = buf[..len].strip_prefix(ANSWER) else {
+            continue;
+        };
+        let Ok(announcement) = serde_json::from_slice::<Announcement>(answer) else {
+            continue;
+        };
+        add_found(&mut found, from.ip(), announcement);
+    }
+    Ok(found)
+}
+
+/// one entry per address, servers elsewhere may well share a name and ports
+fn add_found(found: &mut Vec<Found>, ip: IpAddr, announcement: Announcement) {
+    if found.iter().any(|x| x.ip == ip) {
+        return;
+    }
+    // a server on this machine answers both probes, the loopback answer is the one dropped
+    let same_here = |x: &Found| x.announcement == announcement;
+    if ip.is_loopback() && found.iter().any(same_here) {
+        return;
+    }
+    found.retain(|x| !(x.ip.is_loopback() && same_here(x)));
+    found.push(Found { ip, announcement });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement() -> Announcement {
+        Announcement {
+            name: String::from("ours server"),
+            shares: vec![String::from("home")],
+            rpc_port: 50_051,
+            web_port: 3000,
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_a_server_on_loopback() {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = tokio::spawn(announce(announcement(), port));
+        // the announcer binds as soon as it runs
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let found = discover(port, Duration::from_millis(500)).await.unwrap();
+        server.abort();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].announcement, announcement());
+    }
+
+    #[test]
+    fn twins_on_other_hosts_stay_apart() {
+        let mut found = Vec::new();
+        add_found(&mut found, [192, 168, 1, 2].into(), announcement());
+        add_found(&mut found, [192, 168, 1, 3].into(), announcement());
+        add_found(&mut found, [192, 168, 1, 3].into(), announcement());
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn a_local_server_shows_once() {
+        let mut found = Vec::new();
+        add_found(&mut found, Ipv4Addr::LOCALHOST.into(), announcement());
+        add_found(&mut found, [192, 168, 1, 2].into(), announcement());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ip, IpAddr::from([192, 168, 1, 2]));
+        add_found(&mut found, Ipv4Addr::LOCALHOST.into(), announcement());
+        assert_eq!(found.len(), 1);
+    }
+}
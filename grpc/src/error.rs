@@ -1,5 +1,13 @@
-use std::{io, net::AddrParseError, sync::Arc};
-use tonic::transport;
+use crate::nav::{FsErrorDetails, FsErrorKind};
+use prost::Message;
+use std::{
+    fmt::Display,
+    io,
+    net::AddrParseError,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tonic::{Code, Status, transport};
 
 #[derive(Debug, Clone)]
 pub enum RpcError {
@@ -7,9 +15,276 @@ pub enum RpcError {
     Tonic(Arc<transport::Error>),
     Io(Arc<io::Error>),
     TonicStatus(tonic::Status),
+    ChecksumMismatch(PathBuf),
+    /// the server shows another certificate than the one pinned for it
+    CertificateChanged {
+        expected: String,
+        found: String,
+    },
+    /// the server speaks another protocol version, see [`crate::info::PROTOCOL`]
+    Incompatible {
+        server: u32,
+        client: u32,
+    },
     Other(String),
 }
 
+/// what went wrong, for the UI to word and to decide whether trying again helps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    /// the disk or the quota is full, or the file is too large for it
+    NoSpace,
+    ReadOnly,
+    NotAFolder,
+    IsAFolder,
+    NotEmpty,
+    /// a bad name or a request that makes no sense, like pasting a folder inside itself
+    Invalid,
+    /// held by something else for now
+    Busy,
+    Corrupt,
+    /// the server or the network to it went away
+    Unreachable,
+    /// stopped by the host or by the client
+    Cancelled,
+    /// the pairing token was refused
+    Unauthenticated,
+    Incompatible,
+    CertificateChanged,
+    ChecksumMismatch,
+    Other,
+}
+
+impl ErrorKind {
+    /// whether the same call may work if simply made again later
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Busy | Self::Unreachable | Self::Cancelled | Self::ChecksumMismatch
+        )
+    }
+
+    fn code(self) -> Code {
+        match self {
+            Self::NotFound => Code::NotFound,
+            Self::PermissionDenied | Self::ReadOnly => Code::PermissionDenied,
+            Self::AlreadyExists => Code::AlreadyExists,
+            Self::NoSpace => Code::ResourceExhausted,
+            Self::NotAFolder | Self::IsAFolder | Self::NotEmpty => Code::FailedPrecondition,
+            Self::Invalid => Code::InvalidArgument,
+            Self::Busy | Self::Unreachable => Code::Unavailable,
+            Self::Corrupt | Self::ChecksumMismatch => Code::DataLoss,
+            Self::Cancelled => Code::Cancelled,
+            Self::Unauthenticated => Code::Unauthenticated,
+            Self::Incompatible => Code::Unimplemented,
+            Self::CertificateChanged | Self::Other => Code::Internal,
+        }
+    }
+
+    /// for statuses from servers that send no details
+    fn from_code(code: Code) -> Self {
+        match code {
+            Code::NotFound => Self::NotFound,
+            Code::PermissionDenied => Self::PermissionDenied,
+            Code::AlreadyExists => Self::AlreadyExists,
+            Code::ResourceExhausted => Self::NoSpace,
+            Code::InvalidArgument => Self::Invalid,
+            Code::DataLoss => Self::Corrupt,
+            Code::Unavailable | Code::DeadlineExceeded => Self::Unreachable,
+            Code::Cancelled => Self::Cancelled,
+            Code::Unauthenticated => Self::Unauthenticated,
+            Code::Unimplemented => Self::Incompatible,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::NotFound => "it does not exist",
+            Self::PermissionDenied => "permission denied",
+            Self::AlreadyExists => "it already exists",
+            Self::NoSpace => "there is no space left for it",
+            Self::ReadOnly => "the disk is read only",
+            Self::NotAFolder => "it is not a folder",
+            Self::IsAFolder => "it is a folder",
+            Self::NotEmpty => "the folder is not empty",
+            Self::Invalid => "the request is not valid",
+            Self::Busy => "it is busy, try again later",
+            Self::Corrupt => "the data is corrupt",
+            Self::Unreachable => "the server can not be reached",
+            Self::Cancelled => "it was stopped",
+            Self::Unauthenticated => "the pairing token was refused",
+            Self::Incompatible => "the server runs an incompatible version",
+            Self::CertificateChanged => "the server certificate changed",
+            Self::ChecksumMismatch => "the content does not match the server copy",
+            Self::Other => "something went wrong",
+        };
+        write!(f, "{text}")
+    }
+}
+
+impl From<io::ErrorKind> for ErrorKind {
+    fn from(value: io::ErrorKind) -> Self {
+        use io::ErrorKind as K;
+        match value {
+            K::NotFound => Self::NotFound,
+            K::PermissionDenied => Self::PermissionDenied,
+            K::AlreadyExists => Self::AlreadyExists,
+            K::StorageFull | K::QuotaExceeded | K::FileTooLarge => Self::NoSpace,
+            K::ReadOnlyFilesystem => Self::ReadOnly,
+            K::NotADirectory => Self::NotAFolder,
+            K::IsADirectory => Self::IsAFolder,
+            K::DirectoryNotEmpty => Self::NotEmpty,
+            K::InvalidInput | K::InvalidFilename => Self::Invalid,
+            K::ResourceBusy
+            | K::ExecutableFileBusy
+            | K::WouldBlock
+            | K::Interrupted
+            | K::Deadlock => Self::Busy,
+            K::InvalidData | K::UnexpectedEof => Self::Corrupt,
+            K::TimedOut
+            | K::ConnectionRefused
+            | K::ConnectionReset
+            | K::ConnectionAborted
+            | K::NotConnected
+            | K::BrokenPipe
+            | K::HostUnreachable
+            | K::NetworkUnreachable
+            | K::NetworkDown
+            | K::StaleNetworkFileHandle => Self::Unreachable,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<ErrorKind> for FsErrorKind {
+    fn from(value: ErrorKind) -> Self {
+        match value {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::AlreadyExists => Self::AlreadyExists,
+            ErrorKind::NoSpace => Self::NoSpace,
+            ErrorKind::ReadOnly => Self::ReadOnly,
+            ErrorKind::NotAFolder => Self::NotAFolder,
+            ErrorKind::IsAFolder => Self::IsAFolder,
+            ErrorKind::NotEmpty => Self::NotEmpty,
+            ErrorKind::Invalid => Self::Invalid,
+            ErrorKind::Busy => Self::Busy,
+            ErrorKind::Corrupt => Self::Corrupt,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<FsErrorKind> for ErrorKind {
+    fn from(value: FsErrorKind) -> Self {
+        match value {
+            FsErrorKind::NotFound => Self::NotFound,
+            FsErrorKind::PermissionDenied => Self::PermissionDenied,
+            FsErrorKind::AlreadyExists => Self::AlreadyExists,
+            FsErrorKind::NoSpace => Self::NoSpace,
+            FsErrorKind::ReadOnly => Self::ReadOnly,
+            FsErrorKind::NotAFolder => Self::NotAFolder,
+            FsErrorKind::IsAFolder => Self::IsAFolder,
+            FsErrorKind::NotEmpty => Self::NotEmpty,
+            FsErrorKind::Invalid => Self::Invalid,
+            FsErrorKind::Busy => Self::Busy,
+            FsErrorKind::Corrupt => Self::Corrupt,
+            FsErrorKind::Other => Self::Other,
+        }
+    }
+}
+
+/// a filesystem failure as the server reported it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsFailure {
+    pub kind: ErrorKind,
+    /// what the server was doing, like "open" or "rename"
+    pub operation: String,
+    /// as the client sent it
+    pub path: PathBuf,
+}
+
+/// the status for `err` hit while doing `operation` on `path`, which is the path the client sent
+pub fn fs_status(err: io::Error, operation: &str, path: &Path) -> Status {
+    let kind = ErrorKind::from(err.kind());
+    let details = FsErrorDetails {
+        kind: FsErrorKind::from(kind).into(),
+        operation: operation.to_string(),
+        path: path.to_string_lossy().to_string(),
+    };
+    Status::with_details(
+        kind.code(),
+        format!("{operation} {}: {err}", path.display()),
+        details.encode_to_vec().into(),
+    )
+}
+
+impl RpcError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TonicStatus(status) => match self.fs_failure() {
+                // servers map what they can not name to other, the code may still tell more
+                Some(failure) if failure.kind != ErrorKind::Other => failure.kind,
+                _ => ErrorKind::from_code(status.code()),
+            },
+            Self::Io(err) => err.kind().into(),
+            Self::Tonic(_) => ErrorKind::Unreachable,
+            Self::ChecksumMismatch(_) => ErrorKind::ChecksumMismatch,
+            Self::CertificateChanged { .. } => ErrorKind::CertificateChanged,
+            Self::Incompatible { .. } => ErrorKind::Incompatible,
+            Self::AddrParse(_) | Self::Other(_) => ErrorKind::Other,
+        }
+    }
+
+    /// the details sent with a status, none for other errors or older servers
+    pub fn fs_failure(&self) -> Option<FsFailure> {
+        let Self::TonicStatus(status) = self else {
+            return None;
+        };
+        if status.details().is_empty() {
+            return None;
+        }
+        let details = FsErrorDetails::decode(status.details()).ok()?;
+        Some(FsFailure {
+            kind: details.kind().into(),
+            operation: details.operation,
+            path: details.path.into(),
+        })
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = self.kind();
+        match self {
+            Self::ChecksumMismatch(path) => write!(f, "{}: {kind}", path.display()),
+            Self::Incompatible { server, client } => {
+                write!(f, "{kind}, it speaks {server} and this app {client}")
+            }
+            Self::AddrParse(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Other(err) => write!(f, "{err}"),
+            Self::TonicStatus(status) => match self.fs_failure() {
+                Some(failure) => write!(
+                    f,
+                    "could not {} {}: {kind}",
+                    failure.operation,
+                    failure.path.display()
+                ),
+                None if status.message().is_empty() => write!(f, "{kind}"),
+                None => write!(f, "{}", status.message()),
+            },
+            Self::Tonic(_) | Self::CertificateChanged { .. } => write!(f, "{kind}"),
+        }
+    }
+}
+
 impl From<String> for RpcError {
     fn from(value: String) -> Self {
         Self::Other(value)
@@ -39,3 +314,147 @@ impl From<tonic::Status> for RpcError {
         Self::TonicStatus(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed(kind: io::ErrorKind) -> RpcError {
+        let err = io::Error::new(kind, "boom");
+        fs_status(err, "open", Path::new("s/a.txt")).into()
+    }
+
+    #[test]
+    fn io_kinds_get_their_own_codes() {
+        let cases = [
+            (io::ErrorKind::NotFound, ErrorKind::NotFound, Code::NotFound),
+            (
+                io::ErrorKind::PermissionDenied,
+                ErrorKind::PermissionDenied,
+                Code::PermissionDenied,
+            ),
+            (
+                io::ErrorKind::AlreadyExists,
+                ErrorKind::AlreadyExists,
+                Code::AlreadyExists,
+            ),
+            (
+                io::ErrorKind::StorageFull,
+                ErrorKind::NoSpace,
+                Code::ResourceExhausted,
+            ),
+            (
+                io::ErrorKind::QuotaExceeded,
+                ErrorKind::NoSpace,
+                Code::ResourceExhausted,
+            ),
+            (
+                io::ErrorKind::ReadOnlyFilesystem,
+                ErrorKind::ReadOnly,
+                Code::PermissionDenied,
+            ),
+            (
+                io::ErrorKind::NotADirectory,
+                ErrorKind::NotAFolder,
+                Code::FailedPrecondition,
+            ),
+            (
+                io::ErrorKind::IsADirectory,
+                ErrorKind::IsAFolder,
+                Code::FailedPrecondition,
+            ),
+            (
+                io::ErrorKind::DirectoryNotEmpty,
+                ErrorKind::NotEmpty,
+                Code::FailedPrecondition,
+            ),
+            (
+                io::ErrorKind::InvalidFilename,
+                ErrorKind::Invalid,
+                Code::InvalidArgument,
+            ),
+            (
+                io::ErrorKind::ResourceBusy,
+                ErrorKind::Busy,
+                Code::Unavailable,
+            ),
+            (
+                io::ErrorKind::InvalidData,
+                ErrorKind::Corrupt,
+                Code::DataLoss,
+            ),
+            (
+                io::ErrorKind::TimedOut,
+                ErrorKind::Unreachable,
+                Code::Unavailable,
+            ),
+            (
+                io::ErrorKind::CrossesDevices,
+                ErrorKind::Other,
+                Code::Internal,
+            ),
+        ];
+        for (io_kind, kind, code) in cases {
+            let err = failed(io_kind);
+            let RpcError::TonicStatus(status) = &err else {
+                unreachable!()
+            };
+            assert_eq!(status.code(), code, "{io_kind:?}");
+            assert_eq!(err.kind(), kind, "{io_kind:?}");
+        }
+    }
+
+    #[test]
+    fn details_round_trip() {
+        let err = failed(io::ErrorKind::DirectoryNotEmpty);
+        assert_eq!(
+            err.fs_failure(),
+            Some(FsFailure {
+                kind: ErrorKind::NotEmpty,
+                operation: String::from("open"),
+                path: PathBuf::from("s/a.txt"),
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "could not open s/a.txt: the folder is not empty"
+        );
+    }
+
+    #[test]
+    fn every_sent_kind_comes_back() {
+        let kinds = [
+            ErrorKind::NotFound,
+            ErrorKind::PermissionDenied,
+            ErrorKind::AlreadyExists,
+            ErrorKind::NoSpace,
+            ErrorKind::ReadOnly,
+            ErrorKind::NotAFolder,
+            ErrorKind::IsAFolder,
+            ErrorKind::NotEmpty,
+            ErrorKind::Invalid,
+            ErrorKind::Busy,
+            ErrorKind::Corrupt,
+        ];
+        for kind in kinds {
+            assert_eq!(ErrorKind::from(FsErrorKind::from(kind)), kind);
+        }
+    }
+
+    #[test]
+    fn statuses_without_details_fall_back_to_the_code() {
+        let err = RpcError::from(Status::unavailable("later"));
+        assert_eq!(err.fs_failure(), None);
+        assert_eq!(err.kind(), ErrorKind::Unreachable);
+        assert!(err.kind().is_retryable());
+        assert_eq!(err.to_string(), "later");
+        let err = RpcError::from(Status::unauthenticated(""));
+        assert_eq!(err.kind(), ErrorKind::Unauthenticated);
+        assert!(!err.kind().is_retryable());
+        assert_eq!(err.to_string(), "the pairing token was refused");
+        // other from an older server still reads its code
+        let details = FsErrorDetails::default().encode_to_vec().into();
+        let err = RpcError::from(Status::with_details(Code::NotFound, "gone", details));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
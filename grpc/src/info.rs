@@ -0,0 +1,227 @@
+use crate::{ShareMode, error::RpcError, nav::ServerInfoResponse};
+use std::ops::BitOr;
+
+/// bumped on every change older clients can not follow
+pub const PROTOCOL: u32 = 1;
+
+/// optional features a server has, one bit each so newer servers can add more
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// downloads in ranges that pick up where they stopped
+    pub const RESUME: Self = Self(1);
+    /// uploads and new folders
+    pub const UPLOAD: Self = Self(1 << 1);
+    pub const SEARCH: Self = Self(1 << 2);
+    pub const WATCH: Self = Self(1 << 3);
+    pub const THUMBNAILS: Self = Self(1 << 4);
+    pub const MEDIA_INFO: Self = Self(1 << 5);
+    pub const ARCHIVE: Self = Self(1 << 6);
+    pub const CHECKSUM: Self = Self(1 << 7);
+    /// renaming, deleting, copying and moving
+    pub const EDIT: Self = Self(1 << 8);
+
+    /// everything this build of the server has
+    pub const ALL: Self = Self(
+        Self::RESUME.0
+            | Self::UPLOAD.0
+            | Self::SEARCH.0
+            | Self::WATCH.0
+            | Self::THUMBNAILS.0
+            | Self::MEDIA_INFO.0
+            | Self::ARCHIVE.0
+            | Self::CHECKSUM.0
+            | Self::EDIT.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// unknown bits from newer servers are kept, they just match nothing here
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// who the client is talking to, asked for once right after connecting
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub protocol: u32,
+    pub name: String,
+    pub shares: Vec<String>,
+    pub mode: ShareMode,
+    pub capabilities: Capabilities,
+}
+
+impl ServerInfo {
+    /// refuses servers speaking another protocol than this build
+    pub fn check(&self) -> Result<(), RpcError> {
+        if self.protocol == PROTOCOL {
+            Ok(())
+        } else {
+            Err(RpcError::Incompatible {
+                server: self.protocol,
+                client: PROTOCOL,
+            })
+        }
+    }
+
+    /// whether the server has every bit of `capability` and its mode lets clients use each
+    pub fn can(&self, capability: Capabilities) -> bool {
+        let uses = |bits: Capabilities| capability.0 & bits.0 != 0;
+        let writes = Capabilities::UPLOAD | Capabilities::EDIT;
+        // everything but uploads and edits reads what is shared
+        let reads = capability.0 & !writes.0 != 0;
+        (!uses(Capabilities::UPLOAD) || self.mode.can_upload())
+            && (!uses(Capabilities::EDIT) || self.mode.can_change())
+            && (!reads || self.mode.can_read())
+            && self.capabilities.contains(capability)
+    }
+}
+
+impl From<ServerInfo> for ServerInfoResponse {
+    fn from(value: ServerInfo) -> Self {
+        Self {
+            protocol: value.protocol,
+            name: value.name,
+            shares: value.shares,
+            mode: value.mode.into(),
+            capabilities: value.capabilities.bits(),
+        }
+    }
+}
+
+impl From<ServerInfoResponse> for ServerInfo {
+    fn from(value: ServerInfoResponse) -> Self {
+        Self {
+            protocol: value.protocol,
+            name: value.name,
+            shares: value.shares,
+            // a mode this build does not know is taken as read only
+            mode: ShareMode::try_from(value.mode).unwrap_or(ShareMode::ReadOnly),
+            capabilities: Capabilities::from_bits(value.capabilities),
+        }
+    }
+}
+
+/// the name servers go by unless told otherwise
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| String::from("ours server"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(mode: ShareMode, capabilities: Capabilities) -> ServerInfo {
+        ServerInfo {
+            protocol: PROTOCOL,
+            name: String::from("server"),
+            shares: Vec::new(),
+            mode,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn other_protocols_are_refused() {
+        assert!(
+            info(ShareMode::ReadWrite, Capabilities::ALL)
+                .check()
+                .is_ok()
+        );
+        let older = ServerInfo {
+            protocol: PROTOCOL - 1,
+            ..info(ShareMode::ReadWrite, Capabilities::ALL)
+        };
+        let Err(RpcError::Incompatible { server, client }) = older.check() else {
+            panic!("an older protocol was accepted");
+        };
+        assert_eq!((server, client), (PROTOCOL - 1, PROTOCOL));
+    }
+
+    #[test]
+    fn each_capability_has_its_own_bit() {
+        let all = [
+            Capabilities::RESUME,
+            Capabilities::UPLOAD,
+            Capabilities::SEARCH,
+            Capabilities::WATCH,
+            Capabilities::THUMBNAILS,
+            Capabilities::MEDIA_INFO,
+            Capabilities::ARCHIVE,
+            Capabilities::CHECKSUM,
+            Capabilities::EDIT,
+        ];
+        let mut seen = Capabilities::default();
+        for capability in all {
+            assert_eq!(capability.bits().count_ones(), 1);
+            assert!(!seen.contains(capability));
+            seen = seen | capability;
+        }
+        assert_eq!(seen, Capabilities::ALL);
+    }
+
+    #[test]
+    fn unknown_bits_survive_the_round_trip() {
+        let newer = Capabilities::from_bits(Capabilities::ALL.bits() | 1 << 40);
+        let response = ServerInfoResponse::from(info(ShareMode::ReadOnly, newer));
+        let info = ServerInfo::from(response);
+        assert_eq!(info.capabilities, newer);
+        assert!(info.capabilities.contains(Capabilities::ALL));
+        assert_eq!(info.mode, ShareMode::ReadOnly);
+    }
+
+    #[test]
+    fn capabilities_follow_the_mode() {
+        let read_only = info(ShareMode::ReadOnly, Capabilities::ALL);
+        assert!(read_only.can(Capabilities::SEARCH));
+        assert!(!read_only.can(Capabilities::UPLOAD));
+        assert!(!read_only.can(Capabilities::EDIT));
+
+        let drop_box = info(ShareMode::DropBox, Capabilities::ALL);
+        assert!(drop_box.can(Capabilities::UPLOAD));
+        assert!(!drop_box.can(Capabilities::SEARCH));
+        assert!(!drop_box.can(Capabilities::EDIT));
+
+        let read_write = info(ShareMode::ReadWrite, Capabilities::ALL);
+        assert!(read_write.can(Capabilities::ALL));
+    }
+
+    #[test]
+    fn combined_capabilities_need_every_bit_allowed() {
+        let drop_box = info(ShareMode::DropBox, Capabilities::ALL);
+        assert!(!drop_box.can(Capabilities::UPLOAD | Capabilities::EDIT));
+        assert!(!drop_box.can(Capabilities::UPLOAD | Capabilities::SEARCH));
+        let read_only = info(ShareMode::ReadOnly, Capabilities::ALL);
+        assert!(!read_only.can(Capabilities::SEARCH | Capabilities::UPLOAD));
+        assert!(read_only.can(Capabilities::SEARCH | Capabilities::WATCH));
+    }
+
+    #[test]
+    fn missing_capabilities_are_never_usable() {
+        let old = info(ShareMode::ReadWrite, Capabilities::RESUME);
+        assert!(old.can(Capabilities::RESUME));
+        assert!(!old.can(Capabilities::CHECKSUM));
+        assert!(!old.can(Capabilities::RESUME | Capabilities::CHECKSUM));
+    }
+}
@@ -1,11 +1,31 @@
+pub mod archive;
+pub mod auth;
+mod checksum;
 pub mod client;
+pub mod compression;
+pub mod discovery;
 pub mod error;
+pub mod info;
+pub mod media;
+mod mode;
+mod ops;
+pub mod search;
 pub mod server;
+pub mod shares;
+pub mod throttle;
+pub mod thumbnail;
+pub mod tls;
 pub mod top;
+pub mod transcode;
+pub mod transfers;
+pub mod watch;
 mod nav {
     use tonic::include_proto;
 
     include_proto!("nav.v1");
 }
 
-pub use nav::UnitKind;
+pub use nav::{
+    ArchiveFormat, Compression, ConflictPolicy, SearchSyntax, ShareMode, UnitKind, UploadOutcome,
+    WatchChange,
+};
@@ -0,0 +1,498 @@
+use crate::nav::MediaInfoResponse;
+use serde::Deserialize;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    process::Stdio,
+    time::Duration,
+};
+use tokio::process::Command;
+
+/// the largest `moov` box the fallback reads, it holds the tables of every sample
+const MAX_MOOV: u64 = 64 * 1024 * 1024;
+
+/// what is known about the streams of a video or audio file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    pub duration: Option<Duration>,
+    /// names as ffprobe gives them like "mov,mp4,m4a,3gp,3g2,mj2" or "matroska,webm"
+    pub container: String,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub resolution: Option<(u32, u32)>,
+    /// bits per second
+    pub bitrate: Option<u64>,
+}
+
+impl MediaInfo {
+    /// whether common browsers play the file as it is
+    pub fn browser_playable(&self) -> bool {
+        let video = self.video_codec.as_deref();
+        let audio = self.audio_codec.as_deref();
+        let audio_plays = |codecs: &[&str]| {
+            audio.is_none_or(|x| {
+                codecs.contains(&x) || (codecs.contains(&"pcm") && x.starts_with("pcm_"))
+            })
+        };
+        let container = |name: &str| self.container.split(',').any(|x| x == name);
+        if container("mp4") || container("mov") {
+            video.is_none_or(|x| ["h264", "vp9", "av1"].contains(&x))
+                && audio_plays(&["aac", "mp3", "opus", "flac"])
+        } else if container("webm") || container("matroska") {
+            // matroska plays in browsers only with the codecs webm allows
+            video.is_none_or(|x| ["vp8", "vp9", "av1"].contains(&x))
+                && audio_plays(&["opus", "vorbis"])
+                && (container("webm") || video.is_some() || audio.is_some())
+        } else if container("ogg") {
+            video.is_none() && audio_plays(&["opus", "vorbis", "flac"])
+        } else {
+            ["mp3", "wav", "flac", "aac"].iter().any(|x| container(x))
+                && audio_plays(&["mp3", "pcm", "flac", "aac"])
+        }
+    }
+}
+
+impl Display for MediaInfo {
+    /// one line like "1:02:03 · h264 / aac · 1920x1080 · 4.2 Mb/s · mp4"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(duration) = self.duration {
+            let seconds = duration.as_secs();
+            let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+            parts.push(if h > 0 {
+                format!("{h}:{m:02}:{s:02}")
+            } else {
+                format!("{m}:{s:02}")
+            });
+        }
+        let codecs = [&self.video_codec, &self.audio_codec]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !codecs.is_empty() {
+            parts.push(codecs.join(" / "));
+        }
+        if let Some((width, height)) = self.resolution {
+            parts.push(format!("{width}x{height}"));
+        }
+        match self.bitrate {
+            Some(x) if x >= 1_000_000 => parts.push(format!("{:.1} Mb/s", x as f64 / 1_000_000.)),
+            Some(x) => parts.push(format!("{} kb/s", x / 1000)),
+            None => (),
+        }
+        // ffprobe names mp4 files after quicktime first
+        let mut names = self.container.split(',');
+        let container = if names.clone().any(|x| x == "mp4") {
+            "mp4"
+        } else {
+            names.next().unwrap_or_default()
+        };
+        if !container.is_empty() {
+            parts.push(container.to_string());
+        }
+        write!(f, "{}", parts.join(" · "))
+    }
+}
+
+impl From<MediaInfo> for MediaInfoResponse {
+    fn from(value: MediaInfo) -> Self {
+        let MediaInfo {
+            duration,
+            container,
+            video_codec,
+            audio_codec,
+            resolution,
+            bitrate,
+        } = value;
+        let (width, height) = resolution.unwrap_or_default();
+        Self {
+            duration: duration.map(|x| x.as_secs_f64()).unwrap_or_default(),
+            container,
+            video_codec,
+            audio_codec,
+            width,
+            height,
+            bitrate: bitrate.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<MediaInfoResponse> for MediaInfo {
+    fn from(value: MediaInfoResponse) -> Self {
+        Self {
+            duration: (value.duration > 0.).then(|| Duration::from_secs_f64(value.duration)),
+            container: value.container,
+            video_codec: value.video_codec,
+            audio_codec: value.audio_codec,
+            resolution: (value.width != 0 && value.height != 0)
+                .then_some((value.width, value.height)),
+            bitrate: (value.bitrate != 0).then_some(value.bitrate),
+        }
+    }
+}
+
+/// asks a local ffprobe, without one the container is parsed here which knows less
+pub async fn probe(path: &Path) -> io::Result<MediaInfo> {
+    if let Some(info) = ffprobe(path).await {
+        return Ok(info);
+    }
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || parse(&path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    format: ProbeFormat,
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    format_name: String,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// cover art shows up as a video stream
+    #[serde(default)]
+    disposition: ProbeDisposition,
+}
+
+#[derive(Deserialize, Default)]
+struct ProbeDisposition {
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+/// none when ffprobe is missing or does not know the file
+async fn ffprobe(path: &Path) -> Option<MediaInfo> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let Probe { format, streams } = serde_json::from_slice(&output.stdout).ok()?;
+    let first = |kind: &str| {
+        streams
+            .iter()
+            .find(|x| x.codec_type.as_deref() == Some(kind) && x.disposition.attached_pic == 0)
+    };
+    let video = first("video");
+    let audio = first("audio");
+    Some(MediaInfo {
+        duration: format
+            .duration
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|x| x.is_finite() && *x > 0.)
+            .map(Duration::from_secs_f64),
+        container: format.format_name,
+        video_codec: video.and_then(|x| x.codec_name.clone()),
+        audio_codec: audio.and_then(|x| x.codec_name.clone()),
+        resolution: video.and_then(|x| Some((x.width?, x.height?))),
+        bitrate: format.bit_rate.and_then(|x| x.parse().ok()),
+    })
+}
+
+/// knows mp4 and quicktime files fully, other containers only by name
+fn parse(path: &Path) -> io::Result<MediaInfo> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut magic = [0; 64];
+    let read = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..read];
+    let at = |range: std::ops::Range<usize>| magic.get(range).unwrap_or_default();
+    let container = match (at(0..4), at(4..8), at(8..12)) {
+        (_, b"ftyp", _) => return parse_mp4(&mut file, size),
+        ([0x1a, 0x45, 0xdf, 0xa3], _, _) if contains(magic, b"webm") => "webm",
+        ([0x1a, 0x45, 0xdf, 0xa3], _, _) => "matroska",
+        (b"OggS", _, _) => "ogg",
+        (b"fLaC", _, _) => "flac",
+        (b"RIFF", _, b"WAVE") => "wav",
+        (b"RIFF", _, b"AVI ") => "avi",
+        ([b'I', b'D', b'3', _], _, _) => "mp3",
+        // frame sync, a layer other than 0 is mpeg audio and layer 0 is adts aac
+        ([0xff, x, _, _], _, _) if x & 0xe0 == 0xe0 && x & 0x06 != 0 => "mp3",
+        ([0xff, x, _, _], _, _) if x & 0xf6 == 0xf0 => "aac",
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a known media container",
+            ));
+        }
+    };
+    Ok(MediaInfo {
+        container: container.to_string(),
+        ..Default::default()
+    })
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+/// boxes are a 32 bit size and a four letter type, sizes of 1 are followed by a 64 bit one
+/// and 0 runs to the end
+fn parse_mp4(file: &mut File, size: u64) -> io::Result<MediaInfo> {
+    let invalid = |x: &str| io::Error::new(io::ErrorKind::InvalidData, x.to_string());
+    let mut offset = 0;
+    let mut brand = [0; 4];
+    let mut moov = None;
+    // `offset` never passes `size`, so the room left can not underflow
+    while size - offset >= 8 {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 16];
+        let read = read_up_to(file, &mut header)?;
+        if read < 8 {
+            break;
+        }
+        let (len, skip) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (size - offset, 8),
+            1 if read == 16 => (u64::from_be_bytes(header[8..16].try_into().unwrap()), 16),
+            len => (len as u64, 8),
+        };
+        if len < skip || len > size - offset {
+            return Err(invalid("broken mp4 box"));
+        }
+        match &header[4..8] {
+            b"ftyp" => brand.copy_from_slice(&header[8..12]),
+            b"moov" if len - skip <= MAX_MOOV => {
+                let mut body = vec![0; (len - skip) as usize];
+                file.seek(SeekFrom::Start(offset + skip))?;
+                file.read_exact(&mut body)?;
+                moov = Some(body);
+                break;
+            }
+            _ => (),
+        }
+        offset += len;
+    }
+    let moov = moov.ok_or_else(|| invalid("the mp4 has no readable moov box"))?;
+    let mut info = MediaInfo {
+        container: if &brand == b"qt  " {
+            "mov".to_string()
+        } else {
+            "mov,mp4,m4a,3gp,3g2,mj2".to_string()
+        },
+        ..Default::default()
+    };
+    for (kind, body) in boxes(&moov) {
+        match kind {
+            b"mvhd" => info.duration = mvhd_duration(body),
+            b"trak" => track(body, &mut info),
+            _ => (),
+        }
+    }
+    info.bitrate = info
+        .duration
+        .filter(|x| !x.is_zero())
+        .map(|x| (size as f64 * 8. / x.as_secs_f64()) as u64);
+    Ok(info)
+}
+
+/// the boxes inside `data`, stops at the first broken one
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+        let kind = data.get(4..8)?;
+        let len = if len == 0 { data.len() } else { len };
+        let body = data.get(8..len)?;
+        data = &data[len..];
+        Some((kind, body))
+    })
+}
+
+fn child<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, name| {
+        boxes(data)
+            .find(|(kind, _)| kind == name)
+            .map(|(_, body)| body)
+    })
+}
+
+fn mvhd_duration(body: &[u8]) -> Option<Duration> {
+    let be = |range: std::ops::Range<usize>| -> Option<u64> {
+        Some(
+            body.get(range)?
+                .iter()
+                .fold(0, |acc, x| acc << 8 | *x as u64),
+        )
+    };
+    let (timescale, duration) = match body.first()? {
+        1 => (be(20..24)?, be(24..32)?),
+        _ => (be(12..16)?, be(16..20)?),
+    };
+    (timescale != 0).then(|| Duration::from_secs_f64(duration as f64 / timescale as f64))
+}
+
+/// the codec and size of the first video and audio track
+fn track(trak: &[u8], info: &mut MediaInfo) {
+    let Some(mdia) = child(trak, &[b"mdia"]) else {
+        return;
+    };
+    // the handler type follows the version, flags and a predefined field
+    let Some(handler) = child(mdia, &[b"hdlr"]).and_then(|x| x.get(8..12)) else {
+        return;
+    };
+    // the first sample entry follows the version, flags and entry count
+    let Some(entry) = child(mdia, &[b"minf", b"stbl", b"stsd"]).and_then(|x| x.get(8..)) else {
+        return;
+    };
+    let Some(format) = entry.get(4..8) else {
+        return;
+    };
+    match handler {
+        b"vide" if info.video_codec.is_none() => {
+            info.video_codec = Some(codec_name(format));
+            // visual entries keep the size after 24 bytes of reserved and predefined fields
+            info.resolution = entry.get(32..36).map(|x| {
+                (
+                    u16::from_be_bytes([x[0], x[1]]) as u32,
+                    u16::from_be_bytes([x[2], x[3]]) as u32,
+                )
+            });
+        }
+        b"soun" if info.audio_codec.is_none() => info.audio_codec = Some(codec_name(format)),
+        _ => (),
+    }
+}
+
+/// sample entry types under the names ffprobe uses
+fn codec_name(format: &[u8]) -> String {
+    match format {
+        b"avc1" | b"avc3" => "h264",
+        b"hvc1" | b"hev1" => "hevc",
+        b"vp08" => "vp8",
+        b"vp09" => "vp9",
+        b"av01" => "av1",
+        b"mp4v" => "mpeg4",
+        b"mp4a" => "aac",
+        b"Opus" => "opus",
+        b"fLaC" => "flac",
+        b".mp3" => "mp3",
+        b"ac-3" => "ac3",
+        b"ec-3" => "eac3",
+        b"alac" => "alac",
+        other => return String::from_utf8_lossy(other).trim().to_string(),
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, fs, io::Write};
+
+    fn mp4box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn moov() -> Vec<u8> {
+        let mut mvhd = vec![0u8; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&65000u32.to_be_bytes());
+        mp4box(b"moov", &mp4box(b"mvhd", &mvhd))
+    }
+
+    fn parse_bytes(name: &str, data: &[u8]) -> io::Result<MediaInfo> {
+        let path = temp_dir().join(format!("ours-media-{}-{name}", std::process::id()));
+        fs::File::create(&path).unwrap().write_all(data).unwrap();
+        let result = parse(&path);
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    fn head() -> Vec<u8> {
+        mp4box(b"ftyp", b"isom\0\0\x02\0isom")
+    }
+
+    #[test]
+    fn reads_the_duration() {
+        let mut data = head();
+        data.extend(mp4box(b"mdat", &[7; 100]));
+        data.extend(moov());
+        let info = parse_bytes("plain", &data).unwrap();
+        assert_eq!(info.duration, Some(Duration::from_secs(65)));
+    }
+
+    #[test]
+    fn follows_64_bit_sizes() {
+        let mut data = head();
+        data.extend(1u32.to_be_bytes());
+        data.extend(b"mdat");
+        data.extend(116u64.to_be_bytes());
+        data.extend([7; 100]);
+        data.extend(moov());
+        let info = parse_bytes("large", &data).unwrap();
+        assert_eq!(info.duration, Some(Duration::from_secs(65)));
+    }
+
+    #[test]
+    fn refuses_boxes_past_the_end() {
+        let mut data = head();
+        data.extend(1u32.to_be_bytes());
+        data.extend(b"mdat");
+        data.extend(u64::MAX.to_be_bytes());
+        data.extend(moov());
+        assert!(parse_bytes("huge", &data).is_err());
+
+        let mut data = head();
+        data.extend(1000u32.to_be_bytes());
+        data.extend(b"mdat");
+        data.extend(moov());
+        assert!(parse_bytes("oversized", &data).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_boxes() {
+        let mut data = head();
+        data.extend(moov());
+        data.truncate(data.len() - 10);
+        assert!(parse_bytes("cut", &data).is_err());
+
+        let mut data = head();
+        data.extend(4u32.to_be_bytes());
+        data.extend(b"free");
+        data.extend(moov());
+        assert!(parse_bytes("short", &data).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use crate::ShareMode;
+use tonic::Status;
+
+impl ShareMode {
+    pub const ALL: [Self; 3] = [Self::ReadWrite, Self::ReadOnly, Self::DropBox];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::ReadWrite => "read write",
+            Self::ReadOnly => "read only",
+            Self::DropBox => "drop box",
+        }
+    }
+
+    /// listing and reading files
+    pub fn can_read(self) -> bool {
+        self != Self::DropBox
+    }
+
+    /// adding files and folders
+    pub fn can_upload(self) -> bool {
+        self != Self::ReadOnly
+    }
+
+    /// renaming, moving and deleting what is already shared
+    pub fn can_change(self) -> bool {
+        self == Self::ReadWrite
+    }
+
+    /// refuses the call unless `allowed` holds for this mode
+    pub fn check(self, allowed: fn(Self) -> bool) -> Result<(), Status> {
+        if allowed(self) {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!(
+                "not allowed in {} mode",
+                self.label()
+            )))
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+/// where `from` lands inside `destination`, nothing gets overwritten and
+/// no folder is pasted inside itself
+pub(crate) async fn paste_target(from: &Path, destination: &Path) -> io::Result<PathBuf> {
+    let Some(name) = from.file_name() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no name", from.display()),
+        ));
+    };
+    if destination.starts_with(from) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can not be pasted inside itself", name.display()),
+        ));
+    }
+    let to = destination.join(name);
+    if fs::try_exists(&to).await? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists in the destination", name.display()),
+        ));
+    }
+    Ok(to)
+}
+
+/// the first "name (n).ext" next to `path` that is not taken yet
+pub(crate) async fn free_name(path: &Path) -> io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|x| format!(".{}", x.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{stem} ({n}){extension}"));
+        if !fs::try_exists(&candidate).await? {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+/// copies `from` to `to`, folders with everything in them.
+///
+/// links to files are followed only while they stay inside one of the `roots`, linked
+/// folders are never entered. what is left out makes the copy fail once the rest is done
+pub(crate) async fn copy_tree(roots: &[PathBuf], from: &Path, to: &Path) -> io::Result<()> {
+    let base = from.parent().unwrap_or(from);
+    let mut skipped = Vec::new();
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        let metadata = fs::symlink_metadata(&from).await?;
+        if metadata.is_symlink() {
+            let inside = fs::canonicalize(&from)
+                .await
+                .is_ok_and(|x| roots.iter().any(|root| x.starts_with(root)));
+            if inside && fs::metadata(&from).await?.is_file() {
+                fs::copy(&from, &to).await?;
+            } else {
+                skipped.push(from.strip_prefix(base).unwrap_or(&from).to_path_buf());
+            }
+        } else if metadata.is_dir() {
+            fs::create_dir(&to).await?;
+            let mut entries = fs::read_dir(&from).await?;
+            while let Some(x) = entries.next_entry().await? {
+                pending.push((x.path(), to.join(x.file_name())));
+            }
+        } else {
+            fs::copy(&from, &to).await?;
+        }
+    }
+    match skipped.as_slice() {
+        [] => Ok(()),
+        [link] => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is a linked folder or leaves the shares, it was not copied",
+                link.display()
+            ),
+        )),
+        [link, rest @ ..] => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} and {} more are linked folders or leave the shares, they were not copied",
+                link.display(),
+                rest.len()
+            ),
+        )),
+    }
+}
+
+/// renames when possible, copies then deletes across file systems, the source stays
+/// when anything could not be copied
+pub(crate) async fn move_tree(roots: &[PathBuf], from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            copy_tree(roots, from, to).await?;
+            remove(from, true).await
+        }
+        result => result,
+    }
+}
+
+/// removes a file, a link or a folder, the folder must be empty unless `recursive`
+pub(crate) async fn remove(path: &Path, recursive: bool) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path).await?;
+    if !metadata.is_dir() {
+        fs::remove_file(path).await
+    } else if recursive {
+        fs::remove_dir_all(path).await
+    } else {
+        fs::remove_dir(path).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, os::unix::fs::symlink};
+
+    /// a share holding a folder with a file, a linked folder and a link leaving the share
+    fn tree(name: &str) -> PathBuf {
+        let base = temp_dir().join(format!("ours-ops-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("share/from/inner")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(base.join("share/from/inner/a.txt"), b"a").unwrap();
+        std::fs::write(base.join("outside/secret.txt"), b"no").unwrap();
+        base
+    }
+
+    #[tokio::test]
+    async fn copies_files_and_links_to_files() {
+        let base = tree("files");
+        let share = base.join("share");
+        symlink(share.join("from/inner/a.txt"), share.join("from/b.txt")).unwrap();
+        copy_tree(
+            std::slice::from_ref(&share),
+            &share.join("from"),
+            &share.join("to"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(share.join("to/inner/a.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(share.join("to/b.txt")).unwrap(), b"a");
+        assert!(
+            !std::fs::symlink_metadata(share.join("to/b.txt"))
+                .unwrap()
+                .is_symlink()
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn links_left_out_fail_the_copy() {
+        let base = tree("links");
+        let share = base.join("share");
+        symlink(share.join("from/inner"), share.join("from/linked")).unwrap();
+        symlink(
+            base.join("outside/secret.txt"),
+            share.join("from/secret.txt"),
+        )
+        .unwrap();
+        let err = copy_tree(
+            std::slice::from_ref(&share),
+            &share.join("from"),
+            &share.join("to"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("1 more"), "{err}");
+        // the rest is still copied
+        assert!(share.join("to/inner/a.txt").exists());
+        assert!(!share.join("to/secret.txt").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
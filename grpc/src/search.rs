@@ -0,0 +1,422 @@
+use crate::{
+    SearchSyntax, UnitKind,
+    nav::SearchRequest,
+    shares::{Share, Shares},
+    top::Unit,
+};
+use glob::{MatchOptions, Pattern};
+use regex::{Regex, RegexBuilder};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+const BATCH_SIZE: usize = 512;
+
+/// what the client searches for, unset filters match everything
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub text: String,
+    pub syntax: SearchSyntax,
+    pub kind: Option<UnitKind>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+}
+
+impl Query {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn request(self, path: &Path) -> SearchRequest {
+        let seconds = |x: SystemTime| x.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        SearchRequest {
+            path: path.to_string_lossy().to_string(),
+            query: self.text,
+            syntax: self.syntax.into(),
+            kind: self.kind.map(Into::into),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after: self.modified_after.map(seconds),
+            modified_before: self.modified_before.map(seconds),
+        }
+    }
+}
+
+enum Name {
+    Any,
+    Substring(String),
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+/// a query ready to be matched against units
+pub struct Filter {
+    name: Name,
+    kind: Option<UnitKind>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+}
+
+impl Filter {
+    /// keeps everything, what a plain walk sends
+    pub fn all() -> Self {
+        Self {
+            name: Name::Any,
+            kind: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+        }
+    }
+
+    /// fails on a glob or regex that does not parse
+    pub fn new(query: Query) -> Result<Self, String> {
+        let name = match query.syntax {
+            _ if query.text.is_empty() => Name::Any,
+            SearchSyntax::Substring => Name::Substring(query.text.to_lowercase()),
+            SearchSyntax::Glob => Name::Glob(Pattern::new(&query.text).map_err(|x| x.to_string())?),
+            SearchSyntax::Regex => Name::Regex(
+                RegexBuilder::new(&query.text)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|x| x.to_string())?,
+            ),
+        };
+        Ok(Self {
+            name,
+            kind: query.kind,
+            min_size: query.min_size,
+            max_size: query.max_size,
+            modified_after: query.modified_after,
+            modified_before: query.modified_before,
+        })
+    }
+
+    pub fn matches(&self, unit: &Unit) -> bool {
+        let name = unit.name();
+        let name_matches = match &self.name {
+            Name::Any => true,
+            Name::Substring(x) => name.to_lowercase().contains(x),
+            Name::Glob(x) => x.matches_with(
+                &name,
+                MatchOptions {
+                    case_sensitive: false,
+                    ..Default::default()
+                },
+            ),
+            Name::Regex(x) => x.is_match(&name),
+        };
+        let sized = self.min_size.is_some() || self.max_size.is_some();
+        let size_matches = !sized
+            || (unit.kind != UnitKind::Folder
+                && self.min_size.is_none_or(|x| unit.meta.size >= x)
+                && self.max_size.is_none_or(|x| unit.meta.size <= x));
+        let dated = self.modified_after.is_some() || self.modified_before.is_some();
+        let date_matches = !dated
+            || unit.meta.modified.is_some_and(|modified| {
+                self.modified_after.is_none_or(|x| modified >= x)
+                    && self.modified_before.is_none_or(|x| modified <= x)
+            });
+        name_matches && self.kind.is_none_or(|x| x == unit.kind) && size_matches && date_matches
+    }
+}
+
+impl TryFrom<SearchRequest> for Filter {
+    type Error = String;
+
+    fn try_from(value: SearchRequest) -> Result<Self, Self::Error> {
+        let time = |x: u64| UNIX_EPOCH + Duration::from_secs(x);
+        Self::new(Query {
+            syntax: value.syntax(),
+            kind: value.kind.map(|_| value.kind()),
+            text: value.query,
+            min_size: value.min_size,
+            max_size: value.max_size,
+            modified_after: value.modified_after.map(time),
+            modified_before: value.modified_before.map(time),
+        })
+    }
+}
+
+/// sends every descendant of `path` that `filter` keeps in batches, with paths as clients see them.
+///
+/// the top level walks every share. `max_depth` 1 stops at the direct children,
+/// excluded names are neither sent nor entered and linked folders are never entered,
+/// they may loop or leave the share. stops early once the receiver is gone
+pub async fn walk(
+    shares: &Shares,
+    path: &Path,
+    max_depth: Option<u32>,
+    excludes: Vec<Pattern>,
+    filter: Filter,
+) -> io::Result<ReceiverStream<io::Result<Vec<Unit>>>> {
+    let start = if Shares::is_top(path) {
+        Start::Top(shares.clone())
+    } else {
+        let (share, root) = shares.resolve(path).await?;
+        Start::Folder(share.clone(), root)
+    };
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let walker = Walker {
+            max_depth,
+            excludes,
+            filter,
+            tx,
+            units: Vec::with_capacity(BATCH_SIZE),
+            dirs: Vec::new(),
+        };
+        walker.run(start).await;
+    });
+    Ok(ReceiverStream::new(rx))
+}
+
+enum Start {
+    Top(Shares),
+    Folder(Share, PathBuf),
+}
+
+struct Walker {
+    max_depth: Option<u32>,
+    excludes: Vec<Pattern>,
+    filter: Filter,
+    tx: mpsc::Sender<io::Result<Vec<Unit>>>,
+    units: Vec<Unit>,
+    /// folders left to read, with their share and the depth of what they hold
+    dirs: Vec<(Share, PathBuf, u32)>,
+}
+
+impl Walker {
+    async fn run(mut self, start: Start) {
+        if let Err(err) = self.walk(start).await {
+            let _ = self.tx.send(Err(err)).await;
+        }
+    }
+
+    /// only failing to read where the walk starts ends it, what can not be read below is skipped
+    async fn walk(&mut self, start: Start) -> io::Result<()> {
+        let mut start_dir = None;
+        match start {
+            Start::Top(shares) => {
+                // the shares are the first level, what they hold starts at the second
+                for share in shares.iter() {
+                    let Ok(unit) = share.unit().await else {
+                        continue;
+                    };
+                    let path = share.path.clone();
+                    if !self.visit(share, unit, path, 1).await {
+                        return Ok(());
+                    }
+                }
+            }
+            Start::Folder(share, root) => {
+                start_dir = Some(root.clone());
+                self.dirs.push((share, root, 1));
+            }
+        }
+        while let Some((share, dir, depth)) = self.dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if start_dir.as_ref() == Some(&dir) => return Err(err),
+                Err(_) => continue,
+            };
+            while let Ok(Some(x)) = entries.next_entry().await {
+                let Ok(unit) = Unit::from_entry(&x).await else {
+                    continue;
+                };
+                let Some(relative) = share.relative(&unit.path) else {
+                    continue;
+                };
+                let path = unit.path.clone();
+                if !self
+                    .visit(
+                        &share,
+                        Unit {
+                            path: relative,
+                            ..unit
+                        },
+                        path,
+                        depth,
+                    )
+                    .await
+                {
+                    return Ok(());
+                }
+            }
+        }
+        if !self.units.is_empty() {
+            let _ = self.tx.send(Ok(std::mem::take(&mut self.units))).await;
+        }
+        Ok(())
+    }
+
+    /// `unit` carries the client path and `path` where it is on disk,
+    /// returns false once the receiver is gone
+    async fn visit(&mut self, share: &Share, unit: Unit, path: PathBuf, depth: u32) -> bool {
+        let name = unit.name();
+        if self
+            .excludes
+            .iter()
+            .any(|x| x.matches(&name) || x.matches_path(&unit.path))
+        {
+            return true;
+        }
+        if unit.kind == UnitKind::Folder
+            && !unit.meta.symlink
+            && self.max_depth.is_none_or(|max| depth < max)
+        {
+            self.dirs.push((share.clone(), path, depth + 1));
+        }
+        if self.filter.matches(&unit) {
+            self.units.push(unit);
+        }
+        if self.units.len() == BATCH_SIZE {
+            let units = std::mem::replace(&mut self.units, Vec::with_capacity(BATCH_SIZE));
+            return self.tx.send(Ok(units)).await.is_ok();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::top::UnitMeta;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn unit(path: &str, kind: UnitKind, size: u64, modified: Option<SystemTime>) -> Unit {
+        Unit {
+            path: PathBuf::from(path),
+            kind,
+            meta: UnitMeta {
+                size,
+                modified,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn file(path: &str) -> Unit {
+        unit(path, UnitKind::File, 10, None)
+    }
+
+    fn filter(text: &str, syntax: SearchSyntax) -> Filter {
+        Filter::new(Query {
+            syntax,
+            ..Query::new(text.to_string())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn substrings_match_names_in_any_case() {
+        let filter = filter("RePort", SearchSyntax::Substring);
+        assert!(filter.matches(&file("s/2024 report.pdf")));
+        assert!(filter.matches(&file("s/REPORTS")));
+        assert!(!filter.matches(&file("s/repo.rt")));
+        // only the name is searched, not the folders above it
+        assert!(!filter.matches(&file("s/report/notes.txt")));
+    }
+
+    #[test]
+    fn globs_match_whole_names_in_any_case() {
+        let filter = filter("*.JP*G", SearchSyntax::Glob);
+        assert!(filter.matches(&file("s/a.jpeg")));
+        assert!(filter.matches(&file("s/b.JPG")));
+        assert!(!filter.matches(&file("s/a.jpeg.txt")));
+    }
+
+    #[test]
+    fn regexes_match_in_any_case() {
+        let filter = filter(r"^img_\d{4}\.", SearchSyntax::Regex);
+        assert!(filter.matches(&file("s/IMG_2024.heic")));
+        assert!(!filter.matches(&file("s/old img_2024.heic")));
+        assert!(!filter.matches(&file("s/img_24.heic")));
+    }
+
+    #[test]
+    fn broken_patterns_are_errors() {
+        for (text, syntax) in [("[a", SearchSyntax::Glob), ("(a", SearchSyntax::Regex)] {
+            let query = Query {
+                syntax,
+                ..Query::new(text.to_string())
+            };
+            assert!(Filter::new(query).is_err(), "{text}");
+        }
+        // nothing to parse, everything matches
+        let filter = filter("", SearchSyntax::Regex);
+        assert!(filter.matches(&file("s/anything")));
+    }
+
+    #[test]
+    fn size_bounds_leave_folders_out() {
+        let filter = Filter::new(Query {
+            min_size: Some(10),
+            max_size: Some(20),
+            ..Query::default()
+        })
+        .unwrap();
+        assert!(filter.matches(&unit("s/a", UnitKind::File, 10, None)));
+        assert!(filter.matches(&unit("s/a", UnitKind::Video, 20, None)));
+        assert!(!filter.matches(&unit("s/a", UnitKind::File, 9, None)));
+        assert!(!filter.matches(&unit("s/a", UnitKind::File, 21, None)));
+        assert!(!filter.matches(&unit("s/a", UnitKind::Folder, 15, None)));
+        assert!(Filter::all().matches(&unit("s/a", UnitKind::Folder, 15, None)));
+    }
+
+    #[test]
+    fn date_bounds_need_a_date() {
+        let now = SystemTime::now();
+        let filter = Filter::new(Query {
+            modified_after: Some(now - 2 * DAY),
+            modified_before: Some(now),
+            ..Query::default()
+        })
+        .unwrap();
+        let at = |x| unit("s/a", UnitKind::File, 1, Some(x));
+        assert!(filter.matches(&at(now - DAY)));
+        assert!(filter.matches(&at(now)));
+        assert!(!filter.matches(&at(now - 3 * DAY)));
+        assert!(!filter.matches(&at(now + DAY)));
+        assert!(!filter.matches(&unit("s/a", UnitKind::File, 1, None)));
+    }
+
+    #[test]
+    fn kinds_are_matched_exactly() {
+        let filter = Filter::new(Query {
+            kind: Some(UnitKind::Folder),
+            ..Query::new(String::from("a"))
+        })
+        .unwrap();
+        assert!(filter.matches(&unit("s/a", UnitKind::Folder, 0, None)));
+        assert!(!filter.matches(&unit("s/a", UnitKind::File, 0, None)));
+    }
+
+    #[test]
+    fn requests_carry_the_query_to_the_server() {
+        let query = Query {
+            syntax: SearchSyntax::Glob,
+            kind: Some(UnitKind::Audio),
+            max_size: Some(5),
+            modified_after: Some(UNIX_EPOCH + DAY),
+            ..Query::new(String::from("*.mp3"))
+        };
+        let filter = Filter::try_from(query.request(Path::new("s"))).unwrap();
+        assert!(filter.matches(&unit("s/a.MP3", UnitKind::Audio, 5, Some(UNIX_EPOCH + DAY))));
+        assert!(!filter.matches(&unit("s/a.mp3", UnitKind::Audio, 5, Some(UNIX_EPOCH))));
+        assert!(!filter.matches(&unit("s/a.mp3", UnitKind::File, 5, Some(UNIX_EPOCH + DAY))));
+    }
+}
@@ -1,113 +1,451 @@
 use super::nav::nav_service_server::NavService;
+use crate::checksum::ChecksumCache;
+use crate::compression::{CHUNK_SIZE, compress, decompress, negotiate};
 use crate::nav::upload_request::Data;
 use crate::nav::{
-    DownloadRequest, DownloadResponse, FileSizeRequest, FileSizeResponse, ResumeDownloadRequest,
-    ResumeDownloadResponse, UploadMetadata, UploadRequest, UploadResponse,
+    ArchiveRequest, ArchiveResponse, ChecksumRequest, ChecksumResponse, DeleteRequest,
+    DeleteResponse, DownloadRequest, DownloadResponse, FileSizeRequest, FileSizeResponse,
+    MediaInfoRequest, MediaInfoResponse, MkdirRequest, MkdirResponse, PasteRequest, PasteResponse,
+    ReadRangeRequest, ReadRangeResponse, RenameRequest, RenameResponse, ResumeDownloadRequest,
+    ResumeDownloadResponse, SearchRequest, SearchResponse, ServerInfoRequest, ServerInfoResponse,
+    ThumbnailRequest, ThumbnailResponse, UploadMetadata, UploadRequest, UploadResponse,
+    UploadStatusRequest, UploadStatusResponse, WalkRequest, WalkResponse, WatchRequest,
+    WatchResponse,
 };
 use crate::{
-    error::RpcError,
-    nav::{LsRequest, LsResponse, Unit, UnitKind, nav_service_server::NavServiceServer},
+    Compression, ConflictPolicy, ShareMode, UploadOutcome,
+    error::{RpcError, fs_status},
+    nav::{LsRequest, LsResponse, Unit, nav_service_server::NavServiceServer},
+    top,
 };
-use common::{AUDIO_X, VIDEO_X};
-use std::io::SeekFrom;
+use crate::{
+    archive, auth,
+    info::{self, Capabilities, ServerInfo},
+    media, ops,
+    search::{self, Filter},
+    shares::{Share, Shares},
+    throttle::{Limits, Throttle},
+    thumbnail::{ThumbnailError, Thumbnails},
+    tls,
+    transfers::{Direction, Transfer, Transfers},
+    watch,
+};
+use glob::Pattern;
+use std::io::{self, SeekFrom};
 use std::pin::Pin;
 use std::{
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
 };
-use tokio::fs::{self, File, create_dir_all, remove_file};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::fs::{self, File, OpenOptions, create_dir_all};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tonic::Streaming;
-use tonic::{Request, Response, Status, async_trait, transport::Server};
+use tonic::{
+    Request, Response, Status, async_trait,
+    transport::{Identity, Server, ServerTlsConfig, server::TcpIncoming},
+};
+
+const LS_BATCH_SIZE: usize = 512;
+
+/// uploads land here first and take their real name once complete
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.part"))
+}
+
+/// partial files being written, each by one upload at a time
+#[derive(Debug, Clone, Default)]
+struct Uploading(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl Uploading {
+    /// `None` while another upload writes `partial`
+    fn lock(&self, partial: &Path) -> Option<UploadLock> {
+        let fresh = self.0.lock().unwrap().insert(partial.to_path_buf());
+        fresh.then(|| UploadLock {
+            uploading: self.clone(),
+            partial: partial.to_path_buf(),
+        })
+    }
+}
+
+/// frees the partial file for the next upload when dropped
+struct UploadLock {
+    uploading: Uploading,
+    partial: PathBuf,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.uploading.0.lock().unwrap().remove(&self.partial);
+    }
+}
 
+async fn partial_len(partial: &Path) -> io::Result<u64> {
+    match fs::metadata(partial).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// names what failed and on which path, tonic alone sends most filesystem errors as unknown
+trait OnPath<T> {
+    /// `path` as the client sent it
+    fn on(self, operation: &str, path: &Path) -> Result<T, Status>;
+}
+
+impl<T> OnPath<T> for io::Result<T> {
+    fn on(self, operation: &str, path: &Path) -> Result<T, Status> {
+        self.map_err(|err| fs_status(err, operation, path))
+    }
+}
+
+fn relative_unit(share: &Share, unit: top::Unit) -> Option<Unit> {
+    let path = share.relative(&unit.path)?;
+    Some(top::Unit { path, ..unit }.into())
+}
+
+fn cancelled_by_host() -> Status {
+    Status::cancelled("the transfer was stopped by the host")
+}
+
+/// streams `reader` of `shown` to the client in chunks of up to 1MB, each encoded with
+/// `compression`, wrapped by `wrap`, paced by `throttle` and counted in `transfer`
+fn stream_reader<R, T>(
+    shown: PathBuf,
+    reader: R,
+    compression: Compression,
+    throttle: Throttle,
+    transfer: Transfer,
+    wrap: fn(Vec<u8>, i32) -> T,
+) -> ReceiverStream<Result<T, Status>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    T: Send + 'static,
+{
+    let mut reader = BufReader::new(reader);
+    let (tx, rx) = mpsc::channel::<Result<T, Status>>(100);
+    tokio::spawn(async move {
+        let mut buffer = bytes::BytesMut::with_capacity(CHUNK_SIZE);
+        loop {
+            if transfer.is_cancelled() {
+                return tx.send(Err(cancelled_by_host())).await;
+            }
+            let rb = match reader.read_buf(&mut buffer).await.on("read", &shown) {
+                Ok(rb) => rb,
+                Err(err) => {
+                    return tx.send(Err(err)).await;
+                }
+            };
+            if rb == 0 {
+                break;
+            }
+            let data = match compress(compression, &buffer) {
+                Ok(data) => data,
+                Err(err) => {
+                    return tx.send(Err(err.into())).await;
+                }
+            };
+            // paced by file bytes like the shown rate, a cancel lands even while a low
+            // limit holds the chunk back
+            tokio::select! {
+                _ = throttle.wait(rb) => (),
+                _ = transfer.cancelled() => continue,
+            }
+            tx.send(Ok(wrap(data, compression.into()))).await?;
+            transfer.add(rb);
+            buffer.clear();
+        }
+        Ok(())
+    });
+    ReceiverStream::new(rx)
+}
+
+/// hands on `chunks` made elsewhere, like archives being packed, paced by `throttle`
+/// and counted in `transfer` until the host cancels it
+fn paced<S, T>(
+    mut chunks: S,
+    throttle: Throttle,
+    transfer: Transfer,
+    wrap: fn(Vec<u8>) -> T,
+) -> ReceiverStream<Result<T, Status>>
+where
+    S: Stream<Item = Result<Vec<u8>, Status>> + Unpin + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Result<T, Status>>(4);
+    tokio::spawn(async move {
+        loop {
+            let chunk = tokio::select! {
+                chunk = chunks.next() => chunk,
+                _ = transfer.cancelled() => return tx.send(Err(cancelled_by_host())).await,
+            };
+            let data = match chunk {
+                Some(Ok(data)) => data,
+                Some(Err(err)) => return tx.send(Err(err)).await,
+                None => break,
+            };
+            let len = data.len();
+            tokio::select! {
+                _ = throttle.wait(len) => (),
+                _ = transfer.cancelled() => return tx.send(Err(cancelled_by_host())).await,
+            }
+            tx.send(Ok(wrap(data))).await?;
+            transfer.add(len);
+        }
+        Ok(())
+    });
+    ReceiverStream::new(rx)
+}
+
+#[derive(Clone)]
 pub struct RpcServer {
-    pub target_dir: PathBuf,
+    /// shown to clients so people can tell servers apart
+    pub name: String,
+    pub shares: Shares,
     pub port: u16,
+    /// clients must send it with every call, see [`auth::Pairing`]
+    pub token: String,
+    /// serving is refused without one
+    pub identity: Option<tls::Identity>,
+    pub mode: ShareMode,
+    /// paces downloads, clones given to other servers share the same limits
+    pub limits: Limits,
+    /// what clients are moving right now, for the host to watch and stop
+    pub transfers: Transfers,
+    checksums: ChecksumCache,
+    thumbnails: Thumbnails,
+    uploading: Uploading,
 }
 
 #[async_trait]
 impl NavService for RpcServer {
+    /// open in every mode, clients ask for it before anything else
+    async fn server_info(
+        &self,
+        _: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let info = ServerInfo {
+            protocol: info::PROTOCOL,
+            name: self.name.clone(),
+            shares: self.shares.iter().map(|x| x.name.clone()).collect(),
+            mode: self.mode,
+            capabilities: Capabilities::ALL,
+        };
+        Ok(Response::new(info.into()))
+    }
+
     async fn ls(&self, req: Request<LsRequest>) -> Result<Response<LsResponse>, Status> {
-        let Ok(root) = req.into_inner().path.parse::<PathBuf>();
-        let root = self.target_dir.join(root);
-        let mut dir = fs::read_dir(&root).await?;
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        if Shares::is_top(&shown) {
+            return Ok(Response::new(LsResponse {
+                units: self.top_units().await,
+            }));
+        }
+        let (share, root) = self.locate(&shown).await?;
+        let mut dir = fs::read_dir(&root).await.on("list", &shown)?;
         let mut units = Vec::new();
-        while let Some(x) = dir.next_entry().await? {
-            let kind = if x.file_type().await?.is_dir() {
-                UnitKind::Folder
-            } else {
-                let ex = x.path();
-                let ex = ex.extension().and_then(|x| x.to_str());
-                match ex {
-                    Some(ex) => {
-                        if VIDEO_X.contains(&ex) {
-                            UnitKind::Video
-                        } else if AUDIO_X.contains(&ex) {
-                            UnitKind::Audio
-                        } else {
-                            UnitKind::File
-                        }
-                    }
-                    _ => UnitKind::File,
-                }
-            };
-            let path = x.path();
-            let Ok(path) = path.strip_prefix(&self.target_dir) else {
-                continue;
-            };
-            let unit = Unit {
-                path: path.to_str().unwrap().to_string(),
-                kind: kind.into(),
-            };
-            units.push(unit);
+        while let Some(x) = dir.next_entry().await.on("list", &shown)? {
+            let unit = top::Unit::from_entry(&x).await.on("list", &shown)?;
+            if let Some(unit) = relative_unit(share, unit) {
+                units.push(unit);
+            }
         }
         Ok(Response::new(LsResponse { units }))
     }
 
+    type LsStreamStream = Pin<Box<dyn Stream<Item = Result<LsResponse, Status>> + Send>>;
+    async fn ls_stream(
+        &self,
+        req: Request<LsRequest>,
+    ) -> Result<Response<Self::LsStreamStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        if Shares::is_top(&shown) {
+            let units = self.top_units().await;
+            let output_stream = tokio_stream::once(Ok(LsResponse { units }));
+            return Ok(Response::new(
+                Box::pin(output_stream) as Self::LsStreamStream
+            ));
+        }
+        let (share, root) = self.locate(&shown).await?;
+        let share = share.clone();
+        let mut dir = fs::read_dir(&root).await.on("list", &shown)?;
+        let (tx, rx) = mpsc::channel::<Result<LsResponse, Status>>(16);
+        tokio::spawn(async move {
+            let mut units = Vec::with_capacity(LS_BATCH_SIZE);
+            loop {
+                let x = match dir.next_entry().await.on("list", &shown) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => break,
+                    Err(err) => return tx.send(Err(err)).await,
+                };
+                let unit = match top::Unit::from_entry(&x).await.on("list", &shown) {
+                    Ok(unit) => unit,
+                    Err(err) => return tx.send(Err(err)).await,
+                };
+                if let Some(unit) = relative_unit(&share, unit) {
+                    units.push(unit);
+                }
+                if units.len() == LS_BATCH_SIZE {
+                    let units = std::mem::replace(&mut units, Vec::with_capacity(LS_BATCH_SIZE));
+                    tx.send(Ok(LsResponse { units })).await?;
+                }
+            }
+            if !units.is_empty() {
+                tx.send(Ok(LsResponse { units })).await?;
+            }
+            Ok(())
+        });
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::LsStreamStream
+        ))
+    }
+
+    type WalkStream = Pin<Box<dyn Stream<Item = Result<WalkResponse, Status>> + Send>>;
+    async fn walk(&self, req: Request<WalkRequest>) -> Result<Response<Self::WalkStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let WalkRequest {
+            path,
+            max_depth,
+            excludes,
+        } = req.into_inner();
+        let Ok(root) = path.parse::<PathBuf>();
+        let excludes = excludes
+            .iter()
+            .map(|x| Pattern::new(x))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let output_stream = search::walk(&self.shares, &root, max_depth, excludes, Filter::all())
+            .await
+            .on("list", &root)?
+            .map(move |batch| {
+                let units = batch
+                    .on("list", &root)?
+                    .into_iter()
+                    .map(Unit::from)
+                    .collect();
+                Ok(WalkResponse { units })
+            });
+        Ok(Response::new(Box::pin(output_stream) as Self::WalkStream))
+    }
+
+    type SearchStream = Pin<Box<dyn Stream<Item = Result<SearchResponse, Status>> + Send>>;
+    async fn search(
+        &self,
+        req: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let req = req.into_inner();
+        let Ok(root) = req.path.parse::<PathBuf>();
+        let filter = Filter::try_from(req).map_err(Status::invalid_argument)?;
+        let output_stream = search::walk(&self.shares, &root, None, Vec::new(), filter)
+            .await
+            .on("search", &root)?
+            .map(move |batch| {
+                let units = batch
+                    .on("search", &root)?
+                    .into_iter()
+                    .map(Unit::from)
+                    .collect();
+                Ok(SearchResponse { units })
+            });
+        Ok(Response::new(Box::pin(output_stream) as Self::SearchStream))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchResponse, Status>> + Send>>;
+    async fn watch(
+        &self,
+        req: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(path) = req.into_inner().path.parse::<PathBuf>();
+        let output_stream = watch::watch(&self.shares, &path)
+            .await
+            .on("watch", &path)?
+            .map(move |change| Ok(WatchResponse::from(change.on("watch", &path)?)));
+        Ok(Response::new(Box::pin(output_stream) as Self::WatchStream))
+    }
+
+    async fn thumbnail(
+        &self,
+        req: Request<ThumbnailRequest>,
+    ) -> Result<Response<ThumbnailResponse>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let jpeg = self.thumbnails.get(&path).await.map_err(|err| match err {
+            ThumbnailError::Io(err) => fs_status(err, "read", &shown),
+            err => err.into(),
+        })?;
+        Ok(Response::new(ThumbnailResponse { jpeg }))
+    }
+
+    async fn media_info(
+        &self,
+        req: Request<MediaInfoRequest>,
+    ) -> Result<Response<MediaInfoResponse>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let info = media::probe(&path).await.on("read", &shown)?;
+        Ok(Response::new(info.into()))
+    }
+
     async fn file_size(
         &self,
         req: Request<FileSizeRequest>,
     ) -> Result<Response<FileSizeResponse>, Status> {
-        let Ok(path) = req.into_inner().path.parse::<PathBuf>();
-        let path = self.target_dir.join(path);
-        let len = File::open(path).await?.metadata().await?.len();
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let len = fs::metadata(path).await.on("open", &shown)?.len();
         Ok(Response::new(FileSizeResponse { size: len }))
     }
 
+    async fn checksum(
+        &self,
+        req: Request<ChecksumRequest>,
+    ) -> Result<Response<ChecksumResponse>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let digest = self.checksums.get(path).await.on("read", &shown)?;
+        Ok(Response::new(ChecksumResponse {
+            blake3: digest.to_vec(),
+        }))
+    }
+
     type DownloadStream = Pin<Box<dyn Stream<Item = Result<DownloadResponse, Status>> + Send>>;
     async fn download(
         &self,
         req: Request<DownloadRequest>,
     ) -> Result<Response<Self::DownloadStream>, Status> {
-        let Ok(path) = req.into_inner().path.parse::<PathBuf>();
-        let path = self.target_dir.join(path);
-        let file = File::open(path).await?;
-        let mut file = BufReader::new(file);
-        let (tx, rx) = mpsc::channel::<Result<DownloadResponse, Status>>(100);
-        tokio::spawn(async move {
-            let mut buffer = bytes::BytesMut::with_capacity(1024 * 1024);
-            loop {
-                let rb = match file.read_buf(&mut buffer).await {
-                    Ok(rb) => rb,
-                    Err(err) => {
-                        return tx.send(Err(err.into())).await;
-                    }
-                };
-                if rb == 0 {
-                    break;
-                }
-                tx.send(Ok(DownloadResponse {
-                    data: buffer.to_vec(),
-                }))
-                .await?;
-                buffer.clear();
-            }
-            Ok(())
-        });
-        let output_stream = ReceiverStream::new(rx);
+        self.mode.check(ShareMode::can_read)?;
+        let peer = req.remote_addr();
+        let req = req.into_inner();
+        let Ok(shown) = req.path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let compression = negotiate(req.compression(), &path);
+        let file = File::open(path).await.on("open", &shown)?;
+        let total = file.metadata().await.on("open", &shown)?.len();
+        let output_stream = stream_reader(
+            shown.clone(),
+            file,
+            compression,
+            self.limits.throttle(peer),
+            self.transfers
+                .start(Direction::Download, peer, shown, Some(total)),
+            |data, compression| DownloadResponse { data, compression },
+        );
         Ok(Response::new(
             Box::pin(output_stream) as Self::DownloadStream
         ))
@@ -119,87 +457,610 @@ impl NavService for RpcServer {
         &self,
         req: Request<ResumeDownloadRequest>,
     ) -> Result<Response<Self::ResumeDownloadStream>, Status> {
-        let ResumeDownloadRequest {
-            path,
-            progress_index,
-        } = req.into_inner();
-        let Ok(path) = path.parse::<PathBuf>();
-        let path = self.target_dir.join(path);
-        let file = File::open(path).await?;
-        let mut file = BufReader::new(file);
-        file.seek(SeekFrom::Start(progress_index)).await?;
-        let (tx, rx) = mpsc::channel::<Result<ResumeDownloadResponse, Status>>(1024);
-        tokio::spawn(async move {
-            let mut buffer = bytes::BytesMut::with_capacity(1024 * 1024);
-            loop {
-                let rb = match file.read_buf(&mut buffer).await {
-                    Ok(rb) => rb,
-                    Err(err) => {
-                        return tx.send(Err(err.into())).await;
-                    }
-                };
-                if rb == 0 {
-                    break;
-                }
-                tx.send(Ok(ResumeDownloadResponse {
-                    data: buffer.to_vec(),
-                }))
-                .await?;
-                buffer.clear();
-            }
-            Ok(())
-        });
-        let output_stream = ReceiverStream::new(rx);
+        self.mode.check(ShareMode::can_read)?;
+        let peer = req.remote_addr();
+        let req = req.into_inner();
+        let Ok(shown) = req.path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let compression = negotiate(req.compression(), &path);
+        let mut file = File::open(path).await.on("open", &shown)?;
+        let left = file
+            .metadata()
+            .await
+            .on("open", &shown)?
+            .len()
+            .saturating_sub(req.progress_index);
+        file.seek(SeekFrom::Start(req.progress_index))
+            .await
+            .on("seek", &shown)?;
+        let output_stream = stream_reader(
+            shown.clone(),
+            file,
+            compression,
+            self.limits.throttle(peer),
+            self.transfers
+                .start(Direction::Download, peer, shown, Some(left)),
+            |data, compression| ResumeDownloadResponse { data, compression },
+        );
         Ok(Response::new(
             Box::pin(output_stream) as Self::ResumeDownloadStream
         ))
     }
 
+    type ReadRangeStream = Pin<Box<dyn Stream<Item = Result<ReadRangeResponse, Status>> + Send>>;
+    async fn read_range(
+        &self,
+        req: Request<ReadRangeRequest>,
+    ) -> Result<Response<Self::ReadRangeStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let peer = req.remote_addr();
+        let req = req.into_inner();
+        let Ok(shown) = req.path.parse::<PathBuf>();
+        let path = self.confine(&shown).await?;
+        let compression = negotiate(req.compression(), &path);
+        let mut file = File::open(path).await.on("open", &shown)?;
+        let left = file
+            .metadata()
+            .await
+            .on("open", &shown)?
+            .len()
+            .saturating_sub(req.offset);
+        file.seek(SeekFrom::Start(req.offset))
+            .await
+            .on("seek", &shown)?;
+        let output_stream = stream_reader(
+            shown.clone(),
+            file.take(req.length),
+            compression,
+            self.limits.throttle(peer),
+            self.transfers
+                .start(Direction::Download, peer, shown, Some(left.min(req.length))),
+            |data, compression| ReadRangeResponse { data, compression },
+        );
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::ReadRangeStream
+        ))
+    }
+
+    type ArchiveStream = Pin<Box<dyn Stream<Item = Result<ArchiveResponse, Status>> + Send>>;
+    async fn archive(
+        &self,
+        req: Request<ArchiveRequest>,
+    ) -> Result<Response<Self::ArchiveStream>, Status> {
+        self.mode.check(ShareMode::can_read)?;
+        let peer = req.remote_addr();
+        let req = req.into_inner();
+        let format = req.format();
+        let mut paths = Vec::with_capacity(req.paths.len());
+        for x in &req.paths {
+            let Ok(path) = x.parse::<PathBuf>();
+            paths.extend(self.shares.expand(&path).await.on("reach", &path)?);
+        }
+        // packing reads many files, the error alone tells which one failed
+        let shown = PathBuf::from(req.paths.join(", "));
+        let transfer = self
+            .transfers
+            .start(Direction::Download, peer, shown.clone(), None);
+        let chunks = archive::stream(self.shares.clone(), paths, format)
+            .map(move |chunk| chunk.on("pack", &shown));
+        let output_stream = paced(chunks, self.limits.throttle(peer), transfer, |data| {
+            ArchiveResponse { data }
+        });
+        Ok(Response::new(Box::pin(output_stream) as Self::ArchiveStream))
+    }
+
     async fn upload(
         &self,
         req: Request<Streaming<UploadRequest>>,
     ) -> Result<Response<UploadResponse>, Status> {
+        self.mode.check(ShareMode::can_upload)?;
+        let peer = req.remote_addr();
         let mut ri = req.into_inner();
         let Some(UploadRequest {
-            data:
-                Some(Data::Meta(UploadMetadata {
-                    target_path,
-                    location_path,
-                })),
+            data: Some(Data::Meta(meta)),
         }) = ri.next().await.transpose()?
         else {
             return Err(Status::cancelled("must recieve first message as path"));
         };
-        let Ok(target_path) = target_path.parse::<PathBuf>();
-        let Ok(location_path) = location_path.parse::<PathBuf>();
-        let path = self.target_dir.join(location_path).join(target_path);
-        create_dir_all(&path).await?;
-        let _ = remove_file(&path).await;
-        let file = File::create(&path).await?;
+        let compression = meta.compression();
+        // what is in a drop box stays hidden and untouched, so uploads there always
+        // take a free name and skipping would tell what is already there
+        let conflict = match self.mode {
+            ShareMode::DropBox => ConflictPolicy::Rename,
+            _ => meta.conflict(),
+        };
+        let UploadMetadata {
+            target_path,
+            location_path,
+            offset,
+            size,
+            ..
+        } = meta;
+        let path = self.upload_path(&location_path, &target_path).await?;
+        let Ok(location) = location_path.parse::<PathBuf>();
+        let shown = location.join(&target_path);
+        if conflict == ConflictPolicy::Skip && fs::try_exists(&path).await.on("open", &shown)? {
+            return Ok(self.upload_response(UploadOutcome::Skipped, &path, &shown));
+        }
+        let partial = partial_path(&path);
+        // two writers on one partial file would interleave their bytes
+        let Some(_lock) = self.uploading.lock(&partial) else {
+            return Err(Status::unavailable(format!(
+                "{} is already being uploaded",
+                shown.display()
+            )));
+        };
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await.on("create", &location)?;
+        }
+        let file = if offset == 0 {
+            File::create(&partial).await.on("create", &shown)?
+        } else {
+            let received = partial_len(&partial).await.on("open", &shown)?;
+            if received != offset {
+                return Err(Status::out_of_range(format!(
+                    "upload resumes at {offset} but {received} bytes were received"
+                )));
+            }
+            OpenOptions::new()
+                .append(true)
+                .open(&partial)
+                .await
+                .on("open", &shown)?
+        };
         let mut file = BufWriter::new(file);
+        let transfer = self.transfers.start(
+            Direction::Upload,
+            peer,
+            shown.clone(),
+            (size != 0).then(|| size.saturating_sub(offset)),
+        );
 
-        while let Some(UploadRequest {
-            data: Some(Data::Chunk(data)),
-        }) = ri.next().await.transpose()?
-        {
-            file.write_all(&data).await?;
-            file.flush().await?;
+        loop {
+            let next = tokio::select! {
+                next = ri.next() => next.transpose()?,
+                _ = transfer.cancelled() => return Err(cancelled_by_host()),
+            };
+            match next {
+                Some(UploadRequest {
+                    data: Some(Data::Chunk(data)),
+                }) => {
+                    let data = tokio::task::spawn_blocking(move || decompress(compression, data))
+                        .await
+                        .map_err(|err| Status::internal(err.to_string()))?
+                        .on("decompress", &shown)?;
+                    file.write_all(&data).await.on("write", &shown)?;
+                    file.flush().await.on("write", &shown)?;
+                    transfer.add(data.len());
+                }
+                // the partial file stays for a later resume
+                Some(_) => return Err(Status::aborted("upload stopped by the client")),
+                None => break,
+            }
+        }
+        file.flush().await.on("write", &shown)?;
+        file.into_inner().sync_all().await.on("write", &shown)?;
+
+        let (outcome, path) = match (conflict, fs::try_exists(&path).await.on("open", &shown)?) {
+            (_, false) => (UploadOutcome::Created, path),
+            (ConflictPolicy::Overwrite, true) => (UploadOutcome::Overwritten, path),
+            (ConflictPolicy::Rename, true) => (
+                UploadOutcome::Renamed,
+                ops::free_name(&path).await.on("rename", &shown)?,
+            ),
+            // taken while the upload was running
+            (ConflictPolicy::Skip, true) => {
+                fs::remove_file(&partial).await.on("delete", &shown)?;
+                return Ok(self.upload_response(UploadOutcome::Skipped, &path, &shown));
+            }
+        };
+        fs::rename(&partial, &path).await.on("rename", &shown)?;
+        Ok(self.upload_response(outcome, &path, &shown))
+    }
+
+    async fn upload_status(
+        &self,
+        req: Request<UploadStatusRequest>,
+    ) -> Result<Response<UploadStatusResponse>, Status> {
+        self.mode.check(ShareMode::can_upload)?;
+        let UploadStatusRequest {
+            target_path,
+            location_path,
+        } = req.into_inner();
+        let path = self.upload_path(&location_path, &target_path).await?;
+        let Ok(location) = location_path.parse::<PathBuf>();
+        let received = partial_len(&partial_path(&path))
+            .await
+            .on("open", &location.join(target_path))?;
+        Ok(Response::new(UploadStatusResponse { received }))
+    }
+
+    async fn mkdir(&self, req: Request<MkdirRequest>) -> Result<Response<MkdirResponse>, Status> {
+        self.mode.check(ShareMode::can_upload)?;
+        let Ok(shown) = req.into_inner().path.parse::<PathBuf>();
+        let path = self.confine_entry(&shown).await?;
+        if self.mode == ShareMode::DropBox {
+            // like uploads, nothing tells whether the folder or its parents were there
+            return match fs::create_dir_all(&path).await {
+                Ok(()) => Ok(Response::new(MkdirResponse {})),
+                Err(_) => Err(Status::internal(format!(
+                    "can not create {}",
+                    shown.display()
+                ))),
+            };
+        }
+        fs::create_dir(&path).await.on("create", &shown)?;
+        Ok(Response::new(MkdirResponse {}))
+    }
+
+    async fn rename(
+        &self,
+        req: Request<RenameRequest>,
+    ) -> Result<Response<RenameResponse>, Status> {
+        self.mode.check(ShareMode::can_change)?;
+        let RenameRequest { path, name } = req.into_inner();
+        let mut components = Path::new(&name).components();
+        let (Some(Component::Normal(new_name)), None) = (components.next(), components.next())
+        else {
+            return Err(Status::invalid_argument(format!(
+                "{name} is not a valid name"
+            )));
+        };
+        let Ok(shown) = path.parse::<PathBuf>();
+        let from = self.confine_entry(&shown).await?;
+        let to = from.with_file_name(new_name);
+        if fs::try_exists(&to).await.on("rename", &shown)? {
+            let exists = io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{name} already exists"),
+            );
+            return Err(fs_status(exists, "rename", &shown));
+        }
+        fs::rename(&from, &to).await.on("rename", &shown)?;
+        Ok(Response::new(RenameResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        req: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        self.mode.check(ShareMode::can_change)?;
+        let DeleteRequest { paths, recursive } = req.into_inner();
+        let mut confined = Vec::with_capacity(paths.len());
+        for path in paths {
+            let Ok(shown) = path.parse::<PathBuf>();
+            let path = self.confine_entry(&shown).await?;
+            confined.push((shown, path));
+        }
+        for (shown, path) in confined {
+            if !recursive
+                && fs::symlink_metadata(&path)
+                    .await
+                    .on("delete", &shown)?
+                    .is_dir()
+                && fs::read_dir(&path)
+                    .await
+                    .on("delete", &shown)?
+                    .next_entry()
+                    .await
+                    .on("delete", &shown)?
+                    .is_some()
+            {
+                let not_empty = io::Error::new(
+                    io::ErrorKind::DirectoryNotEmpty,
+                    format!("{} is not empty", shown.display()),
+                );
+                return Err(fs_status(not_empty, "delete", &shown));
+            }
+            ops::remove(&path, recursive).await.on("delete", &shown)?;
+        }
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn copy(&self, req: Request<PasteRequest>) -> Result<Response<PasteResponse>, Status> {
+        self.mode.check(ShareMode::can_change)?;
+        for (shown, from, to) in self.paste_pairs(req.into_inner()).await? {
+            ops::copy_tree(&self.shares.roots(), &from, &to)
+                .await
+                .on("copy", &shown)?;
+        }
+        Ok(Response::new(PasteResponse {}))
+    }
+
+    async fn r#move(&self, req: Request<PasteRequest>) -> Result<Response<PasteResponse>, Status> {
+        self.mode.check(ShareMode::can_change)?;
+        for (shown, from, to) in self.paste_pairs(req.into_inner()).await? {
+            ops::move_tree(&self.shares.roots(), &from, &to)
+                .await
+                .on("move", &shown)?;
         }
-        Ok(Response::new(UploadResponse {}))
+        Ok(Response::new(PasteResponse {}))
     }
 }
 
 impl RpcServer {
-    pub fn new(target_dir: PathBuf, port: u16) -> Self {
-        Self { target_dir, port }
+    pub fn new(shares: Shares, port: u16) -> Self {
+        Self {
+            name: info::hostname(),
+            shares,
+            port,
+            token: auth::pairing_token(),
+            identity: None,
+            mode: ShareMode::ReadWrite,
+            limits: Limits::default(),
+            transfers: Transfers::default(),
+            checksums: ChecksumCache::default(),
+            thumbnails: Thumbnails::default(),
+            uploading: Uploading::default(),
+        }
+    }
+
+    pub fn identity(self, identity: tls::Identity) -> Self {
+        Self {
+            identity: Some(identity),
+            ..self
+        }
+    }
+
+    pub fn mode(self, mode: ShareMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub fn token(self, token: String) -> Self {
+        Self { token, ..self }
+    }
+
+    /// the share `path` is in and where it points on disk
+    async fn locate(&self, path: &Path) -> Result<(&Share, PathBuf), Status> {
+        self.shares.resolve(path).await.on("reach", path)
+    }
+
+    async fn confine(&self, path: &Path) -> Result<PathBuf, Status> {
+        self.locate(path).await.map(|(_, path)| path)
+    }
+
+    /// confines the parent only, so changes hit a link itself and not what it points at
+    async fn confine_entry(&self, path: &Path) -> Result<PathBuf, Status> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if !Shares::is_top(parent) => {
+                Ok(self.confine(parent).await?.join(name))
+            }
+            _ => Err(Status::permission_denied(
+                "the shared folders themselves can not be changed",
+            )),
+        }
+    }
+
+    async fn top_units(&self) -> Vec<Unit> {
+        let units = self.shares.units().await;
+        units.into_iter().map(Unit::from).collect()
+    }
+
+    async fn upload_path(&self, location_path: &str, target_path: &str) -> Result<PathBuf, Status> {
+        let Ok(target_path) = target_path.parse::<PathBuf>();
+        let Ok(location_path) = location_path.parse::<PathBuf>();
+        self.confine_entry(&location_path.join(target_path)).await
+    }
+
+    /// a drop box answers as if every upload were new, under the name it was sent with
+    fn upload_response(
+        &self,
+        outcome: UploadOutcome,
+        path: &Path,
+        shown: &Path,
+    ) -> Response<UploadResponse> {
+        if self.mode == ShareMode::DropBox {
+            return Response::new(UploadResponse {
+                outcome: UploadOutcome::Created.into(),
+                path: shown.to_string_lossy().to_string(),
+            });
+        }
+        let path = self
+            .shares
+            .iter()
+            .find_map(|x| x.relative(path))
+            .unwrap_or_default();
+        Response::new(UploadResponse {
+            outcome: outcome.into(),
+            path: path.to_string_lossy().to_string(),
+        })
     }
+
+    /// each pasted path as the client sent it, where it is and where it goes
+    async fn paste_pairs(
+        &self,
+        req: PasteRequest,
+    ) -> Result<Vec<(PathBuf, PathBuf, PathBuf)>, Status> {
+        let Ok(shown) = req.destination.parse::<PathBuf>();
+        let destination = self.confine(&shown).await?;
+        if !fs::metadata(&destination)
+            .await
+            .on("open", &shown)?
+            .is_dir()
+        {
+            let not_folder = io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a folder", req.destination),
+            );
+            return Err(fs_status(not_folder, "paste into", &shown));
+        }
+        let mut pairs = Vec::with_capacity(req.paths.len());
+        for path in req.paths {
+            let Ok(path) = path.parse::<PathBuf>();
+            let from = self.confine_entry(&path).await?;
+            let to = ops::paste_target(&from, &destination)
+                .await
+                .on("paste", &path)?;
+            pairs.push((path, from, to));
+        }
+        Ok(pairs)
+    }
+
     pub async fn serve(self) -> Result<(), RpcError> {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), self.port);
+        let listener = TcpListener::bind(addr).await?;
+        self.serve_on(listener).await
+    }
+
+    /// serves on a listener the caller already bound, `port` is not looked at
+    pub(crate) async fn serve_on(mut self, listener: TcpListener) -> Result<(), RpcError> {
+        let Some(identity) = &self.identity else {
+            return Err(String::from("there is no certificate to serve with").into());
+        };
+        let identity = Identity::from_pem(&identity.cert_pem, &identity.key_pem);
+        self.shares = self.shares.canonicalize()?;
+        let pairing = auth::Pairing::new(&self.token);
+        // what tonic sets on the listeners it binds itself
+        let incoming = TcpIncoming::from(listener).with_nodelay(Some(true));
         Server::builder()
-            .add_service(NavServiceServer::new(self))
-            .serve(addr)
+            .tls_config(ServerTlsConfig::new().identity(identity))?
+            .add_service(NavServiceServer::with_interceptor(self, pairing))
+            .serve_with_incoming(incoming)
             .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ArchiveFormat, client::RpcClient};
+    use std::{env::temp_dir, time::Duration};
+
+    /// a server on a free port sharing a folder named "s", so client paths start with "s/"
+    /// also gives a clone of the server, its limits and transfers are the running ones
+    async fn serve(name: &str, mode: ShareMode) -> (PathBuf, RpcClient, RpcServer) {
+        let base = temp_dir().join(format!("ours-server-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let share = base.join("s");
+        std::fs::create_dir_all(&share).unwrap();
+        std::fs::write(share.join("y.txt"), b"old").unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let identity = tls::Identity::load_or_create(&base.join("conf")).unwrap();
+        let server = RpcServer::new(Shares::default().with(share.clone()), port)
+            .identity(identity)
+            .token("ABCD-EFGH".to_string())
+            .mode(mode);
+        // connecting waits in the backlog of the bound listener until the server accepts
+        tokio::spawn(server.clone().serve_on(listener));
+        let client = RpcClient::new((Ipv4Addr::LOCALHOST, port).into(), "ABCD-EFGH", None)
+            .await
+            .unwrap();
+        (share, client, server)
+    }
+
+    fn local_file(share: &Path, name: &str, data: &[u8]) -> PathBuf {
+        let local = share.parent().unwrap().join(name);
+        std::fs::write(&local, data).unwrap();
+        local
+    }
+
+    #[tokio::test]
+    async fn uploads_resume_where_they_stopped() {
+        let (share, client, _) = serve("resume", ShareMode::ReadWrite).await;
+        let data: Vec<u8> = (0..300_000u32).map(|x| (x % 251) as u8).collect();
+        let local = local_file(&share, "big.bin", &data);
+        // as if an earlier upload died part way
+        std::fs::write(share.join(".big.bin.part"), &data[..123_456]).unwrap();
+        let received = client.clone().upload_status(Path::new("s"), &local).await;
+        assert_eq!(received.unwrap(), 123_456);
+        let response = client
+            .clone()
+            .resume_upload("s".into(), local.clone(), ConflictPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(response.outcome(), UploadOutcome::Created);
+        assert_eq!(std::fs::read(share.join("big.bin")).unwrap(), data);
+        assert!(!share.join(".big.bin.part").exists());
+        let received = client.upload_status(Path::new("s"), &local).await;
+        assert_eq!(received.unwrap(), 0);
+        std::fs::remove_dir_all(share.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn conflicts_follow_the_policy() {
+        let (share, client, _) = serve("conflicts", ShareMode::ReadWrite).await;
+        let local = local_file(&share, "y.txt", b"new");
+        let upload = |conflict| client.clone().upload("s".into(), local.clone(), conflict);
+
+        let response = upload(ConflictPolicy::Skip).await.unwrap();
+        assert_eq!(response.outcome(), UploadOutcome::Skipped);
+        assert_eq!(std::fs::read(share.join("y.txt")).unwrap(), b"old");
+
+        let response = upload(ConflictPolicy::Rename).await.unwrap();
+        assert_eq!(response.outcome(), UploadOutcome::Renamed);
+        assert_eq!(response.path, "s/y (1).txt");
+        assert_eq!(std::fs::read(share.join("y (1).txt")).unwrap(), b"new");
+
+        let response = upload(ConflictPolicy::Overwrite).await.unwrap();
+        assert_eq!(response.outcome(), UploadOutcome::Overwritten);
+        assert_eq!(std::fs::read(share.join("y.txt")).unwrap(), b"new");
+        std::fs::remove_dir_all(share.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_boxes_tell_nothing_of_what_is_there() {
+        let (share, client, _) = serve("dropbox", ShareMode::DropBox).await;
+        let local = local_file(&share, "y.txt", b"new");
+        for conflict in [ConflictPolicy::Skip, ConflictPolicy::Overwrite] {
+            let response = client
+                .clone()
+                .upload("s".into(), local.clone(), conflict)
+                .await
+                .unwrap();
+            assert_eq!(response.outcome(), UploadOutcome::Created);
+            assert_eq!(response.path, "s/y.txt");
+        }
+        assert_eq!(std::fs::read(share.join("y.txt")).unwrap(), b"old");
+        assert_eq!(std::fs::read(share.join("y (1).txt")).unwrap(), b"new");
+        assert_eq!(std::fs::read(share.join("y (2).txt")).unwrap(), b"new");
+        std::fs::create_dir(share.join("there")).unwrap();
+        for folder in ["s/there", "s/new", "s/missing/parent"] {
+            client.clone().mkdir(folder.into()).await.unwrap();
+        }
+        assert!(share.join("missing/parent").is_dir());
+        let err = client.mkdir("s/y.txt".into()).await.unwrap_err();
+        assert_eq!(err.to_string(), "can not create s/y.txt");
+        std::fs::remove_dir_all(share.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn archives_are_tracked_and_can_be_cancelled() {
+        let (share, client, server) = serve("archive", ShareMode::ReadWrite).await;
+        std::fs::write(share.join("big.bin"), vec![7; 4 * 1024 * 1024]).unwrap();
+        server.limits.set_global(512 * 1024);
+        let mut stream = client
+            .archive_stream(&["s".into()], ArchiveFormat::Tar)
+            .await
+            .unwrap();
+        stream.next().await.unwrap().unwrap();
+        let listed = server.transfers.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, Path::new("s"));
+        server.transfers.cancel_all();
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match stream.next().await {
+                    Some(Ok(_)) => continue,
+                    Some(Err(status)) => return status.code(),
+                    None => return tonic::Code::Ok,
+                }
+            }
+        });
+        assert_eq!(ended.await.unwrap(), tonic::Code::Cancelled);
+        std::fs::remove_dir_all(share.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn one_upload_writes_a_partial_at_a_time() {
+        let uploading = Uploading::default();
+        let partial = Path::new("/share/.y.txt.part");
+        let lock = uploading.lock(partial).unwrap();
+        assert!(uploading.lock(partial).is_none());
+        assert!(uploading.lock(Path::new("/share/.z.txt.part")).is_some());
+        drop(lock);
+        assert!(uploading.lock(partial).is_some());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    UnitKind,
+    top::{Unit, UnitMeta},
+};
+use common::jail;
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+use tokio::fs;
+
+/// a folder the server exposes under `name`
+#[derive(Clone, Debug)]
+pub struct Share {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Share {
+    /// `path` as clients see it, starting with the share name
+    pub fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let rest = path.strip_prefix(&self.path).ok()?;
+        Some(Path::new(&self.name).join(rest))
+    }
+
+    /// the share itself as a folder of the top level
+    pub async fn unit(&self) -> io::Result<Unit> {
+        let metadata = fs::metadata(&self.path).await?;
+        Ok(Unit {
+            path: PathBuf::from(&self.name),
+            kind: UnitKind::Folder,
+            meta: UnitMeta::new(self.name.as_ref(), &metadata, false),
+        })
+    }
+}
+
+/// the shared folders, clients see them as virtual folders at the top level
+#[derive(Clone, Debug, Default)]
+pub struct Shares(Vec<Share>);
+
+impl Shares {
+    /// adds `path` named after its folder, taken names get a number like "name (2)"
+    pub fn add(&mut self, path: PathBuf) {
+        let base = path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("share"));
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.get(&name).is_some() {
+            n += 1;
+            name = format!("{base} ({n})");
+        }
+        self.0.push(Share { name, path });
+    }
+
+    pub fn with(mut self, path: PathBuf) -> Self {
+        self.add(path);
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|x| x.name != name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Share> {
+        self.0.iter().find(|x| x.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Share> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// every shared folder, links may point anywhere inside them
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.0.iter().map(|x| x.path.clone()).collect()
+    }
+
+    /// resolves the paths once so listings can be mapped back to share names
+    pub fn canonicalize(&self) -> io::Result<Self> {
+        self.0
+            .iter()
+            .map(|x| {
+                Ok(Share {
+                    name: x.name.clone(),
+                    path: x.path.canonicalize()?,
+                })
+            })
+            .collect::<io::Result<_>>()
+            .map(Self)
+    }
+
+    /// whether `path` names the virtual top level that holds the shares
+    pub fn is_top(path: &Path) -> bool {
+        path.components().all(|x| x == Component::CurDir)
+    }
+
+    /// the share `path` starts with and where it points on disk, see [`jail::resolve`].
+    /// following the links on the way blocks, so it runs off the async workers
+    pub async fn resolve(&self, path: &Path) -> io::Result<(&Share, PathBuf)> {
+        let mut components = path.components().skip_while(|x| *x == Component::CurDir);
+        let name = match components.next() {
+            Some(Component::Normal(name)) => name.to_string_lossy(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the top level only lists the shared folders",
+                ));
+            }
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is outside the shared folders", path.display()),
+                ));
+            }
+        };
+        let Some(share) = self.get(&name) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} is not shared"),
+            ));
+        };
+        let root = share.path.clone();
+        let inside = components.collect::<PathBuf>();
+        let path = tokio::task::spawn_blocking(move || jail::resolve(&root, &inside))
+            .await
+            .map_err(io::Error::other)??;
+        Ok((share, path))
+    }
+
+    /// the folders `path` stands for in an archive, the top level packs every share
+    pub async fn expand(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if Self::is_top(path) {
+            Ok(self.roots())
+        } else {
+            self.resolve(path).await.map(|(_, path)| vec![path])
+        }
+    }
+
+    /// the listing of the top level, one folder per share. shares that went missing or
+    /// can not be read are left out, the others stay reachable
+    pub async fn units(&self) -> Vec<Unit> {
+        let mut units = Vec::with_capacity(self.0.len());
+        for share in &self.0 {
+            if let Ok(unit) = share.unit().await {
+                units.push(unit);
+            }
+        }
+        units
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    /// two shared folders both named "x" and a file beside them
+    fn shared_roots(name: &str) -> PathBuf {
+        let base = temp_dir().join(format!("ours-shares-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("one/x/inner")).unwrap();
+        std::fs::create_dir_all(base.join("two/x")).unwrap();
+        std::fs::write(base.join("one/x/inner/file.txt"), b"ok").unwrap();
+        std::fs::write(base.join("one/secret.txt"), b"no").unwrap();
+        base
+    }
+
+    fn shares(base: &Path) -> Shares {
+        Shares::default()
+            .with(base.join("one/x"))
+            .with(base.join("two/x"))
+            .canonicalize()
+            .unwrap()
+    }
+
+    #[test]
+    fn taken_names_get_a_number() {
+        let shares = Shares::default()
+            .with("/one/x".into())
+            .with("/two/x".into())
+            .with("/three/x".into());
+        let names = shares.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["x", "x (2)", "x (3)"]);
+        assert_eq!(shares.get("x (2)").unwrap().path, Path::new("/two/x"));
+    }
+
+    #[test]
+    fn paths_are_shown_under_the_share_name() {
+        let share = Share {
+            name: String::from("x (2)"),
+            path: PathBuf::from("/two/x"),
+        };
+        assert_eq!(
+            share.relative(Path::new("/two/x/a/b.txt")).unwrap(),
+            Path::new("x (2)/a/b.txt")
+        );
+        assert_eq!(
+            share.relative(Path::new("/two/x")).unwrap(),
+            Path::new("x (2)")
+        );
+        assert_eq!(share.relative(Path::new("/two/xy/a")), None);
+        assert_eq!(share.relative(Path::new("/one/x/a")), None);
+    }
+
+    #[test]
+    fn the_top_level_is_empty_or_dots() {
+        assert!(Shares::is_top(Path::new("")));
+        assert!(Shares::is_top(Path::new(".")));
+        assert!(Shares::is_top(Path::new("./.")));
+        assert!(!Shares::is_top(Path::new("x")));
+        assert!(!Shares::is_top(Path::new("./x")));
+    }
+
+    #[tokio::test]
+    async fn resolves_inside_each_share() {
+        let base = shared_roots("inside");
+        let shares = shares(&base);
+        let one = base.join("one/x").canonicalize().unwrap();
+        let two = base.join("two/x").canonicalize().unwrap();
+        let (share, path) = shares.resolve(Path::new("x/inner/file.txt")).await.unwrap();
+        assert_eq!(
+            (share.name.as_str(), path),
+            ("x", one.join("inner/file.txt"))
+        );
+        let (share, path) = shares.resolve(Path::new("./x (2)")).await.unwrap();
+        assert_eq!((share.name.as_str(), path), ("x (2)", two.clone()));
+        // upload targets do not exist yet
+        let (_, path) = shares.resolve(Path::new("x (2)/new/a.txt")).await.unwrap();
+        assert_eq!(path, two.join("new/a.txt"));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_escapes_through_a_share_name() {
+        let base = shared_roots("escape");
+        let shares = shares(&base);
+        for path in [
+            "x/../secret.txt",
+            "x/inner/../../secret.txt",
+            "../one/secret.txt",
+            "/x",
+        ] {
+            let err = shares.resolve(Path::new(path)).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{path}");
+        }
+        // the top level is only listed, never resolved
+        let err = shares.resolve(Path::new("")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_shares() {
+        let base = shared_roots("unknown");
+        let shares = shares(&base);
+        for path in ["y/file.txt", "x (3)", "one"] {
+            let err = shares.resolve(Path::new(path)).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound, "{path}");
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expands_the_top_level_to_every_share() {
+        let base = shared_roots("expand");
+        let shares = shares(&base);
+        assert_eq!(shares.expand(Path::new("")).await.unwrap(), shares.roots());
+        let inner = shares.expand(Path::new("x/inner")).await.unwrap();
+        assert_eq!(inner, [base.join("one/x/inner").canonicalize().unwrap()]);
+        assert!(shares.expand(Path::new("x/..")).await.is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_shares_are_left_out_of_the_top_level() {
+        let base = shared_roots("units");
+        let shares = shares(&base);
+        std::fs::remove_dir_all(base.join("two/x")).unwrap();
+        let units = shares.units().await;
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].path, Path::new("x"));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// the longest a transfer sleeps before looking at the limits again
+const SLICE: Duration = Duration::from_millis(100);
+
+/// bytes allowed so far against one rate, overdrawn by a chunk and paid back by waiting
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    last: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            available: 0.,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    /// takes `len` bytes at `rate` bytes per second and gives how many are owed
+    fn take(&mut self, rate: u64, len: usize) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if rate == 0 {
+            self.available = 0.;
+            return 0.;
+        }
+        let rate = rate as f64;
+        // at most a second of saved up bytes, idle transfers do not get to burst
+        self.available = (self.available + elapsed * rate).min(rate) - len as f64;
+        (-self.available).max(0.)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    global: Bucket,
+    /// with the count of transfers still running on each connection
+    connections: HashMap<SocketAddr, (usize, Bucket)>,
+}
+
+/// rates in bytes per second shared by every transfer and by each connection, 0 is unlimited,
+/// clones share them so a change shows on transfers already running
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    global: Arc<AtomicU64>,
+    per_connection: Arc<AtomicU64>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Limits {
+    pub fn global(&self) -> u64 {
+        self.global.load(Ordering::Relaxed)
+    }
+
+    pub fn per_connection(&self) -> u64 {
+        self.per_connection.load(Ordering::Relaxed)
+    }
+
+    pub fn set_global(&self, rate: u64) {
+        self.global.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn set_per_connection(&self, rate: u64) {
+        self.per_connection.store(rate, Ordering::Relaxed);
+    }
+
+    /// paces one transfer, transfers without a known `peer` count as their own connection
+    pub fn throttle(&self, peer: Option<SocketAddr>) -> Throttle {
+        if let Some(peer) = peer {
+            let mut buckets = self.buckets.lock().unwrap();
+            buckets.connections.entry(peer).or_default().0 += 1;
+        }
+        Throttle {
+            limits: self.clone(),
+            peer,
+            own: Mutex::default(),
+        }
+    }
+}
+
+/// dropping it forgets the connection once its last transfer is done
+#[derive(Debug)]
+pub struct Throttle {
+    limits: Limits,
+    peer: Option<SocketAddr>,
+    own: Mutex<Bucket>,
+}
+
+impl Throttle {
+    /// waits until `len` more bytes fit in both limits, in slices so a limit changed
+    /// meanwhile applies to what is left
+    pub async fn wait(&self, len: usize) {
+        let mut owed = {
+            let (global, per_connection) = (self.limits.global(), self.limits.per_connection());
+            let mut buckets = self.limits.buckets.lock().unwrap();
+            let global = buckets.global.take(global, len);
+            let connection = match self.peer.and_then(|x| buckets.connections.get_mut(&x)) {
+                Some((_, bucket)) => bucket.take(per_connection, len),
+                None => self.own.lock().unwrap().take(per_connection, len),
+            };
+            [global, connection]
+        };
+        loop {
+            let rates = [self.limits.global(), self.limits.per_connection()].map(|x| x as f64);
+            let mut wait = 0f64;
+            for (owed, rate) in owed.iter_mut().zip(rates) {
+                // a lifted limit forgives what was owed to it
+                if rate == 0. {
+                    *owed = 0.;
+                }
+                if *owed > 0. {
+                    wait = wait.max(*owed / rate);
+                }
+            }
+            if wait <= 0. {
+                return;
+            }
+            let slice = Duration::from_secs_f64(wait).min(SLICE);
+            let started = Instant::now();
+            tokio::time::sleep(slice).await;
+            let slept = started.elapsed().as_secs_f64();
+            for (owed, rate) in owed.iter_mut().zip(rates) {
+                *owed -= slept * rate;
+            }
+        }
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        let Some(peer) = self.peer else {
+            return;
+        };
+        let mut buckets = self.limits.buckets.lock().unwrap();
+        if let Some((count, _)) = buckets.connections.get_mut(&peer) {
+            *count -= 1;
+            if *count == 0 {
+                buckets.connections.remove(&peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(available: f64, idle: Duration) -> Bucket {
+        Bucket {
+            available,
+            last: Instant::now() - idle,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_with_time() {
+        let mut empty = bucket(0., Duration::from_millis(500));
+        assert_eq!(empty.take(1000, 400), 0.);
+        assert!((empty.available - 100.).abs() < 10.);
+        let mut owing = bucket(-500., Duration::ZERO);
+        assert!((owing.take(1000, 100) - 600.).abs() < 10.);
+    }
+
+    #[test]
+    fn idle_buckets_save_at_most_a_second() {
+        let mut idle = bucket(0., Duration::from_secs(60));
+        assert!((idle.take(1000, 3000) - 2000.).abs() < 1.);
+    }
+
+    #[test]
+    fn unlimited_buckets_owe_nothing() {
+        let mut owing = bucket(-500., Duration::ZERO);
+        assert_eq!(owing.take(0, 1 << 20), 0.);
+        assert_eq!(owing.available, 0.);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_rate() {
+        let limits = Limits::default();
+        limits.set_global(10_000);
+        let throttle = limits.throttle(None);
+        let started = Instant::now();
+        throttle.wait(2000).await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(180), "{waited:?}");
+        assert!(waited < Duration::from_secs(1), "{waited:?}");
+    }
+
+    #[tokio::test]
+    async fn a_lifted_limit_frees_waiting_transfers() {
+        let limits = Limits::default();
+        limits.set_per_connection(100);
+        let throttle = limits.throttle("127.0.0.1:1".parse().ok());
+        let started = Instant::now();
+        let lift = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            limits.set_per_connection(0);
+        };
+        // 10 seconds of bytes at the first limit
+        tokio::join!(throttle.wait(1000), lift);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn connections_are_forgotten_with_their_last_transfer() {
+        let limits = Limits::default();
+        let peer = "127.0.0.1:1".parse().ok();
+        let one = limits.throttle(peer);
+        let two = limits.throttle(peer);
+        drop(one);
+        assert_eq!(limits.buckets.lock().unwrap().connections.len(), 1);
+        drop(two);
+        assert!(limits.buckets.lock().unwrap().connections.is_empty());
+    }
+}
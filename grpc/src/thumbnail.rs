@@ -0,0 +1,298 @@
+use crate::UnitKind;
+use common::IMAGE_X;
+use image::ImageFormat;
+use std::{
+    fmt::Display,
+    fs::Metadata,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::UNIX_EPOCH,
+};
+use tokio::{fs, process::Command, sync::Semaphore};
+use tonic::Status;
+
+/// the longest side of a thumbnail in pixels
+const SIZE: u32 = 256;
+/// decoding and ffmpeg are heavy, more renders than this wait their turn
+const RENDERS: usize = 4;
+/// bytes of thumbnails kept on disk before the oldest are removed
+const CACHE_LIMIT: u64 = 256 * 1024 * 1024;
+/// the cache size is checked once every this many saves
+const EVICT_EVERY: usize = 32;
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    /// only images and videos have thumbnails
+    Unsupported,
+    Io(io::Error),
+    Image(String),
+    /// ffmpeg could not start, most likely it is not installed
+    FfmpegSpawn(PathBuf),
+    /// ffmpeg failed or gave no frame
+    FfmpegWait(PathBuf),
+}
+
+impl From<io::Error> for ThumbnailError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "only images and videos have thumbnails"),
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Image(err) => write!(f, "can not decode the image: {err}"),
+            Self::FfmpegSpawn(_) => write!(f, "ffmpeg is not installed on the server"),
+            Self::FfmpegWait(path) => write!(f, "ffmpeg found no frame in {}", path.display()),
+        }
+    }
+}
+
+impl From<ThumbnailError> for Status {
+    fn from(value: ThumbnailError) -> Self {
+        match value {
+            ThumbnailError::Io(err) => err.into(),
+            ThumbnailError::Unsupported => Status::invalid_argument(value.to_string()),
+            ThumbnailError::FfmpegSpawn(_) => Status::unavailable(value.to_string()),
+            ThumbnailError::Image(_) | ThumbnailError::FfmpegWait(_) => {
+                Status::internal(value.to_string())
+            }
+        }
+    }
+}
+
+/// small jpeg previews kept on disk, keyed by path, modification time and size
+#[derive(Debug, Clone)]
+pub struct Thumbnails {
+    /// nothing is kept without a home folder to keep it in
+    dir: Option<PathBuf>,
+    limit: u64,
+    renders: Arc<Semaphore>,
+}
+
+/// in the home folder rather than the shared temp folder, where others could
+/// plant or read thumbnails
+impl Default for Thumbnails {
+    fn default() -> Self {
+        Self {
+            dir: std::env::home_dir().map(|x| x.join(".ours").join("thumbnails")),
+            limit: CACHE_LIMIT,
+            renders: Arc::new(Semaphore::new(RENDERS)),
+        }
+    }
+}
+
+impl Thumbnails {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Some(dir),
+            ..Default::default()
+        }
+    }
+
+    /// bytes kept on disk, the oldest thumbnails go first past it
+    pub fn limit(self, limit: u64) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// the thumbnail of `path`, rendered the first time it is asked for
+    pub async fn get(&self, path: &Path) -> Result<Vec<u8>, ThumbnailError> {
+        let metadata = fs::metadata(path).await?;
+        let is_video = UnitKind::from_path(path, metadata.is_dir()) == UnitKind::Video;
+        if !is_video && !is_image(path, &metadata) {
+            return Err(ThumbnailError::Unsupported);
+        }
+        let cached = self
+            .dir
+            .as_ref()
+            .map(|x| x.join(key(path, &metadata)).with_extension("jpg"));
+        if let Some(cached) = &cached {
+            match fs::read(cached).await {
+                Ok(jpeg) => return Ok(jpeg),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let jpeg = {
+            let _permit = self.renders.acquire().await.map_err(io::Error::other)?;
+            if is_video {
+                render_video(path).await?
+            } else {
+                render_image(path.to_path_buf()).await?
+            }
+        };
+        // the cache only saves work, a thumbnail that can not be kept is still sent
+        if let (Some(dir), Some(cached)) = (&self.dir, cached) {
+            let _ = save(dir, &cached, &jpeg, self.limit).await;
+        }
+        Ok(jpeg)
+    }
+}
+
+fn is_image(path: &Path, metadata: &Metadata) -> bool {
+    metadata.is_file()
+        && path
+            .extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| IMAGE_X.contains(&x.to_lowercase().as_str()))
+}
+
+fn key(path: &Path, metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    hasher.update(&modified.as_nanos().to_le_bytes());
+    hasher.update(&metadata.len().to_le_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// written aside then renamed so readers never see half a thumbnail
+async fn save(dir: &Path, cached: &Path, jpeg: &[u8], limit: u64) -> io::Result<()> {
+    static SAVES: AtomicUsize = AtomicUsize::new(0);
+    let saves = SAVES.fetch_add(1, Ordering::Relaxed);
+    create_private(dir).await?;
+    let part = cached.with_extension(format!("{saves}.part"));
+    fs::write(&part, jpeg).await?;
+    fs::rename(&part, cached).await?;
+    if saves.is_multiple_of(EVICT_EVERY) {
+        evict(dir, limit).await?;
+    }
+    Ok(())
+}
+
+/// only the owner can list or read the thumbnails, they show what is shared
+async fn create_private(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+        builder.mode(0o700);
+        builder.create(dir).await?;
+        // an older cache may have been made with looser rights
+        fs::set_permissions(dir, Permissions::from_mode(0o700)).await
+    }
+    #[cfg(not(unix))]
+    builder.create(dir).await
+}
+
+/// removes the oldest thumbnails until the rest fit in `limit` bytes
+async fn evict(dir: &Path, limit: u64) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    let mut kept = Vec::new();
+    let mut total = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        total += metadata.len();
+        kept.push((
+            metadata.modified().unwrap_or(UNIX_EPOCH),
+            metadata.len(),
+            entry.path(),
+        ));
+    }
+    kept.sort();
+    for (_, len, path) in kept {
+        if total <= limit {
+            break;
+        }
+        if fs::remove_file(&path).await.is_ok() {
+            total -= len;
+        }
+    }
+    Ok(())
+}
+
+async fn render_image(path: PathBuf) -> Result<Vec<u8>, ThumbnailError> {
+    tokio::task::spawn_blocking(move || {
+        let image = image::open(&path).map_err(|x| ThumbnailError::Image(x.to_string()))?;
+        // jpeg has no alpha channel
+        let thumbnail = image.thumbnail(SIZE, SIZE).into_rgb8();
+        let mut jpeg = Cursor::new(Vec::new());
+        thumbnail
+            .write_to(&mut jpeg, ImageFormat::Jpeg)
+            .map_err(|x| ThumbnailError::Image(x.to_string()))?;
+        Ok(jpeg.into_inner())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// asks a local ffmpeg for one telling frame out of the first ones
+async fn render_video(path: &Path) -> Result<Vec<u8>, ThumbnailError> {
+    let scale = format!("thumbnail,scale={SIZE}:{SIZE}:force_original_aspect_ratio=decrease");
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-vf", &scale, "-frames:v", "1"])
+        .args(["-f", "image2pipe", "-c:v", "mjpeg", "pipe:1"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|_| ThumbnailError::FfmpegSpawn(path.to_path_buf()))?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(ThumbnailError::FfmpegWait(path.to_path_buf()));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, time::Duration};
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("ours-thumbnails-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_cache_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = cache_dir("private");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        save(&dir, &dir.join("a.jpg"), b"jpeg", CACHE_LIMIT)
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_oldest_go_first() {
+        let dir = cache_dir("evict");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join(name), [0; 100]).unwrap();
+            // modification times need to differ even on coarse clocks
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        evict(&dir, 250).await.unwrap();
+        assert!(!dir.join("a").exists());
+        assert!(dir.join("b").exists() && dir.join("c").exists());
+        evict(&dir, 1000).await.unwrap();
+        assert!(dir.join("b").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
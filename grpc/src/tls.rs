@@ -0,0 +1,308 @@
+use crate::error::RpcError;
+use hyper_util::rt::TokioIo;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+
+/// the only name certificates are issued for, clients connect by ip so it is never resolved
+pub const SERVER_NAME: &str = "ours";
+
+fn generate_pems() -> io::Result<(String, String)> {
+    let rcgen::CertifiedKey { cert, signing_key } =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+            .map_err(io::Error::other)?;
+    Ok((cert.pem(), signing_key.serialize_pem()))
+}
+
+/// the self signed certificate of this install
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+}
+
+impl Identity {
+    /// reads the certificate kept in `dir`, it is created on the first run
+    pub fn load_or_create(dir: &Path) -> io::Result<Self> {
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        let (cert_pem, key_pem) = match (
+            fs::read_to_string(&cert_path),
+            fs::read_to_string(&key_path),
+        ) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            (Err(cert), Err(key))
+                if cert.kind() == io::ErrorKind::NotFound
+                    && key.kind() == io::ErrorKind::NotFound =>
+            {
+                let (cert, key) = generate_pems()?;
+                fs::create_dir_all(dir)?;
+                fs::write(&cert_path, &cert)?;
+                write_private(&key_path, &key)?;
+                (cert, key)
+            }
+            // a new certificate would break every pinned client, so only the first run makes one
+            (Err(err), _) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("can not read {}: {err}", cert_path.display()),
+                ));
+            }
+            (_, Err(err)) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("can not read {}: {err}", key_path.display()),
+                ));
+            }
+        };
+        Self::from_pems(cert_pem, key_pem)
+    }
+
+    fn from_pems(cert_pem: String, key_pem: String) -> io::Result<Self> {
+        let der = CertificateDer::from_pem_slice(cert_pem.as_bytes()).map_err(io::Error::other)?;
+        Ok(Self {
+            fingerprint: fingerprint(&der),
+            cert_pem,
+            key_pem,
+        })
+    }
+
+    /// rustls settings for servers outside tonic, h2 is offered before http/1.1
+    pub fn server_config(&self) -> io::Result<ServerConfig> {
+        let cert =
+            CertificateDer::from_pem_slice(self.cert_pem.as_bytes()).map_err(io::Error::other)?;
+        let key =
+            PrivateKeyDer::from_pem_slice(self.key_pem.as_bytes()).map_err(io::Error::other)?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .map_err(io::Error::other)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+/// what the qr code carries and users compare, blake3 of the certificate
+pub fn fingerprint(der: &[u8]) -> String {
+    blake3::hash(der).to_hex().to_string()
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    pinned: Option<String>,
+    changed: Option<String>,
+}
+
+/// trusts only the pinned certificate, or pins the first one it meets
+#[derive(Debug)]
+struct PinVerifier {
+    state: Arc<Mutex<PinState>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let seen = fingerprint(end_entity);
+        let mut state = self.state.lock().unwrap();
+        match state.pinned.as_deref() {
+            Some(pinned) if pinned != seen => {
+                state.changed = Some(seen);
+                Err(rustls::Error::General(
+                    "the server certificate changed".to_string(),
+                ))
+            }
+            Some(_) => Ok(ServerCertVerified::assertion()),
+            None => {
+                state.pinned = Some(seen);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// connects over tls trusting only `pinned`, or the first certificate seen when it is `None`.
+///
+/// returns the channel with the fingerprint it is pinned to
+pub(crate) async fn pinned_channel(
+    addr: SocketAddr,
+    pinned: Option<String>,
+) -> Result<(Channel, String), RpcError> {
+    let provider = Arc::new(ring::default_provider());
+    let state = Arc::new(Mutex::new(PinState {
+        pinned: pinned.clone(),
+        changed: None,
+    }));
+    let verifier = Arc::new(PinVerifier {
+        state: state.clone(),
+        provider: provider.clone(),
+    });
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| RpcError::from(err.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+
+    // tls is done here, tonic only sees an already encrypted stream
+    let channel = Endpoint::from_shared(format!("http://{addr}"))
+        .map_err(|err| RpcError::from(err.to_string()))?
+        .connect_with_connector(service_fn(move |_: Uri| {
+            let connector = connector.clone();
+            async move {
+                let tcp = TcpStream::connect(addr).await?;
+                let name = ServerName::try_from(SERVER_NAME).map_err(io::Error::other)?;
+                let tls = connector.connect(name, tcp).await?;
+                Ok::<_, io::Error>(TokioIo::new(tls))
+            }
+        }))
+        .await;
+
+    let state = state.lock().unwrap();
+    match (channel, &state.changed) {
+        (Ok(channel), _) => Ok((channel, state.pinned.clone().unwrap_or_default())),
+        (Err(_), Some(found)) => Err(RpcError::CertificateChanged {
+            expected: pinned.unwrap_or_default(),
+            found: found.clone(),
+        }),
+        (Err(err), None) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::RpcServer, shares::Shares};
+    use std::{net::Ipv4Addr, path::PathBuf};
+    use tokio::net::TcpListener;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ours-tls-{}-{name}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn the_certificate_is_kept_across_runs() {
+        let dir = temp_dir("kept");
+        let first = Identity::load_or_create(&dir).unwrap();
+        let second = Identity::load_or_create(&dir).unwrap();
+        assert_eq!(first.fingerprint, second.fingerprint);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_lost_key_is_not_replaced() {
+        let dir = temp_dir("lost");
+        let first = Identity::load_or_create(&dir).unwrap();
+        fs::remove_file(dir.join("key.pem")).unwrap();
+        let err = Identity::load_or_create(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let cert = fs::read_to_string(dir.join("cert.pem")).unwrap();
+        assert_eq!(cert, first.cert_pem);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// an app server for `dir` with the certificate kept in it
+    async fn serve(dir: &Path) -> (SocketAddr, Identity) {
+        let identity = Identity::load_or_create(&dir.join("conf")).unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RpcServer::new(Shares::default().with(dir.to_path_buf()), addr.port())
+            .identity(identity.clone());
+        tokio::spawn(server.serve_on(listener));
+        (addr, identity)
+    }
+
+    #[tokio::test]
+    async fn the_first_certificate_is_pinned_then_trusted() {
+        let dir = temp_dir("pin");
+        let (addr, identity) = serve(&dir).await;
+        let (_, pinned) = pinned_channel(addr, None).await.unwrap();
+        assert_eq!(pinned, identity.fingerprint);
+        let (_, again) = pinned_channel(addr, Some(pinned.clone())).await.unwrap();
+        assert_eq!(again, pinned);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_changed_certificate_is_refused() {
+        let dir = temp_dir("changed");
+        let (addr, identity) = serve(&dir).await;
+        let other = fingerprint(b"the certificate from before");
+        let Err(RpcError::CertificateChanged { expected, found }) =
+            pinned_channel(addr, Some(other.clone())).await
+        else {
+            panic!("connected to a server with another certificate");
+        };
+        assert_eq!(expected, other);
+        assert_eq!(found, identity.fingerprint);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,14 @@
-use super::nav::UnitKind;
-use common::assets::IconName;
-
-use std::path::PathBuf;
+use super::nav::{self, UnitKind};
+use common::{AUDIO_X, IMAGE_X, VIDEO_X, assets::IconName};
+
+use std::{
+    ffi::OsStr,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs::{self, DirEntry};
 
 #[derive(Default, Clone, Debug)]
 pub enum SelectedState {
@@ -83,6 +90,91 @@ impl Selected {
 pub struct Unit {
     pub path: PathBuf,
     pub kind: UnitKind,
+    pub meta: UnitMeta,
+}
+
+#[derive(Default, Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
+pub struct UnitMeta {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub mode: u32,
+    pub readonly: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl UnitMeta {
+    pub fn new(name: &OsStr, metadata: &Metadata, symlink: bool) -> Self {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions());
+        #[cfg(not(unix))]
+        let mode = 0;
+        #[cfg(windows)]
+        let hidden_attr = std::os::windows::fs::MetadataExt::file_attributes(metadata) & 0x2 != 0;
+        #[cfg(not(windows))]
+        let hidden_attr = false;
+
+        Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode,
+            readonly: metadata.permissions().readonly(),
+            hidden: hidden_attr || name.to_str().is_some_and(|x| x.starts_with('.')),
+            symlink,
+        }
+    }
+}
+
+impl UnitKind {
+    pub fn from_path(path: &Path, is_dir: bool) -> Self {
+        if is_dir {
+            return UnitKind::Folder;
+        }
+        match path.extension().and_then(|x| x.to_str()) {
+            Some(ex) if VIDEO_X.contains(&ex) => UnitKind::Video,
+            Some(ex) if AUDIO_X.contains(&ex) => UnitKind::Audio,
+            _ => UnitKind::File,
+        }
+    }
+}
+
+impl From<nav::Unit> for Unit {
+    fn from(value: nav::Unit) -> Self {
+        let Ok(path) = value.path.parse::<PathBuf>();
+        Self {
+            path,
+            kind: value.kind(),
+            meta: UnitMeta {
+                size: value.size,
+                modified: (value.modified != 0)
+                    .then(|| UNIX_EPOCH + Duration::from_secs(value.modified)),
+                mode: value.mode,
+                readonly: value.readonly,
+                hidden: value.hidden,
+                symlink: value.symlink,
+            },
+        }
+    }
+}
+
+impl From<Unit> for nav::Unit {
+    fn from(value: Unit) -> Self {
+        let Unit { path, kind, meta } = value;
+        Self {
+            path: path.to_str().unwrap().to_string(),
+            kind: kind.into(),
+            size: meta.size,
+            modified: meta
+                .modified
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            mode: meta.mode,
+            readonly: meta.readonly,
+            hidden: meta.hidden,
+            symlink: meta.symlink,
+        }
+    }
 }
 
 impl From<UnitKind> for IconName {
@@ -97,10 +189,58 @@ impl From<UnitKind> for IconName {
 }
 
 impl Unit {
+    /// symlinks are followed so linked folders are listed as folders
+    pub async fn from_entry(entry: &DirEntry) -> io::Result<Self> {
+        let path = entry.path();
+        let symlink = entry.file_type().await?.is_symlink();
+        let metadata = if symlink {
+            match fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(_) => entry.metadata().await?,
+            }
+        } else {
+            entry.metadata().await?
+        };
+        Ok(Self {
+            kind: UnitKind::from_path(&path, metadata.is_dir()),
+            meta: UnitMeta::new(&entry.file_name(), &metadata, symlink),
+            path,
+        })
+    }
+
+    /// like [`Unit::from_entry`] for a path that was not listed
+    pub async fn from_path(path: PathBuf) -> io::Result<Self> {
+        let symlink = fs::symlink_metadata(&path).await?.is_symlink();
+        let metadata = match fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(_) if symlink => fs::symlink_metadata(&path).await?,
+            Err(err) => return Err(err),
+        };
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        Ok(Self {
+            kind: UnitKind::from_path(&path, metadata.is_dir()),
+            meta: UnitMeta::new(&name, &metadata, symlink),
+            path,
+        })
+    }
+
     pub fn name(&self) -> String {
         self.path.file_name().unwrap().to_str().unwrap().to_string()
     }
 
+    /// videos and images, see [`crate::thumbnail::Thumbnails`]
+    pub fn has_thumbnail(&self) -> bool {
+        match self.kind {
+            UnitKind::Video => true,
+            UnitKind::File => self
+                .path
+                .extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| IMAGE_X.contains(&x.to_lowercase().as_str())),
+            _ => false,
+        }
+    }
+
     pub fn icon(&self) -> &'static [u8] {
         IconName::from(self.kind).get()
     }
@@ -0,0 +1,102 @@
+use bytes::Bytes;
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    process::{Child, ChildStdout, Command},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_stream::Stream;
+use tokio_util::io::ReaderStream;
+
+/// each transcode keeps a core or more busy
+const MAX_TRANSCODES: usize = 2;
+
+#[derive(Debug)]
+pub enum TranscodeError {
+    /// every slot is taken until a running transcode ends
+    Busy,
+    /// ffmpeg could not start, most likely it is not installed
+    FfmpegSpawn(PathBuf),
+}
+
+impl Display for TranscodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Busy => write!(f, "too many videos are being converted, try again later"),
+            Self::FfmpegSpawn(_) => write!(f, "ffmpeg is not installed on the server"),
+        }
+    }
+}
+
+/// converts videos browsers can not play into fragmented mp4 while they are watched
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    slots: Arc<Semaphore>,
+}
+
+impl Default for Transcoder {
+    fn default() -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(MAX_TRANSCODES)),
+        }
+    }
+}
+
+impl Transcoder {
+    /// the converted video from `start` on, fails at once when every slot is taken
+    pub fn start(&self, path: &Path, start: Duration) -> Result<Transcode, TranscodeError> {
+        let permit = self
+            .slots
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| TranscodeError::Busy)?;
+        let mut child = Command::new("ffmpeg")
+            .args(["-v", "error", "-ss"])
+            .arg(format!("{:.3}", start.as_secs_f64()))
+            .arg("-i")
+            .arg(path)
+            .args(["-map", "0:v:0", "-map", "0:a:0?"])
+            .args([
+                "-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p",
+            ])
+            .args(["-c:a", "aac", "-ac", "2"])
+            // fragments play while the rest is still being made
+            .args(["-movflags", "frag_keyframe+empty_moov+default_base_moof"])
+            .args(["-f", "mp4", "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|_| TranscodeError::FfmpegSpawn(path.to_path_buf()))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Transcode {
+            output: ReaderStream::new(stdout),
+            _child: child,
+            _permit: permit,
+        })
+    }
+}
+
+/// the output of one ffmpeg run, dropping it kills the process and frees its slot
+pub struct Transcode {
+    output: ReaderStream<ChildStdout>,
+    _child: Child,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Stream for Transcode {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.output).poll_next(cx)
+    }
+}
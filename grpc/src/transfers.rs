@@ -0,0 +1,245 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+/// how far back the shown rate looks
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// a transfer as it stood when [`Transfers::list`] was called
+#[derive(Debug, Clone)]
+pub struct TransferInfo {
+    pub id: u64,
+    pub direction: Direction,
+    pub peer: Option<SocketAddr>,
+    /// as the client sees it
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// bytes this transfer should move, unknown for uploads from older clients
+    pub total: Option<u64>,
+    pub started: Instant,
+    /// bytes per second over the last second or so
+    pub rate: u64,
+}
+
+#[derive(Debug)]
+struct Progress {
+    bytes: u64,
+    /// of the last window that closed
+    rate: Option<u64>,
+    /// when the open window started and the bytes done by then
+    window: (Instant, u64),
+}
+
+impl Progress {
+    /// a window left open for long means the transfer stalled, so it counts instead
+    fn rate(&self) -> u64 {
+        let (since, from) = self.window;
+        let open = since.elapsed();
+        match self.rate {
+            Some(rate) if open < RATE_WINDOW => rate,
+            _ => ((self.bytes - from) as f64 / open.as_secs_f64().max(0.001)) as u64,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    direction: Direction,
+    peer: Option<SocketAddr>,
+    path: PathBuf,
+    total: Option<u64>,
+    started: Instant,
+    progress: Mutex<Progress>,
+    cancel: CancellationToken,
+}
+
+/// every download and upload running on the servers sharing it
+#[derive(Debug, Clone, Default)]
+pub struct Transfers {
+    entries: Arc<Mutex<Vec<Arc<Entry>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Transfers {
+    /// tracks a new transfer until the returned handle is dropped
+    pub fn start(
+        &self,
+        direction: Direction,
+        peer: Option<SocketAddr>,
+        path: PathBuf,
+        total: Option<u64>,
+    ) -> Transfer {
+        let now = Instant::now();
+        let entry = Arc::new(Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            direction,
+            peer,
+            path,
+            total,
+            started: now,
+            progress: Mutex::new(Progress {
+                bytes: 0,
+                rate: None,
+                window: (now, 0),
+            }),
+            cancel: CancellationToken::new(),
+        });
+        self.entries.lock().unwrap().push(entry.clone());
+        Transfer {
+            entry,
+            transfers: self.clone(),
+        }
+    }
+
+    /// oldest first
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|entry| {
+                let progress = entry.progress.lock().unwrap();
+                TransferInfo {
+                    id: entry.id,
+                    direction: entry.direction,
+                    peer: entry.peer,
+                    path: entry.path.clone(),
+                    bytes: progress.bytes,
+                    total: entry.total,
+                    started: entry.started,
+                    rate: progress.rate(),
+                }
+            })
+            .collect()
+    }
+
+    /// the transfer stops at its next chunk, the client sees it fail
+    pub fn cancel(&self, id: u64) {
+        let entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter().find(|x| x.id == id) {
+            entry.cancel.cancel();
+        }
+    }
+
+    /// every running transfer stops at its next chunk
+    pub fn cancel_all(&self) {
+        for entry in self.entries.lock().unwrap().iter() {
+            entry.cancel.cancel();
+        }
+    }
+}
+
+/// the running side of a tracked transfer
+#[derive(Debug)]
+pub struct Transfer {
+    entry: Arc<Entry>,
+    transfers: Transfers,
+}
+
+impl Transfer {
+    pub fn add(&self, bytes: usize) {
+        let mut progress = self.entry.progress.lock().unwrap();
+        progress.bytes += bytes as u64;
+        let (since, from) = progress.window;
+        let elapsed = since.elapsed();
+        if elapsed >= RATE_WINDOW {
+            progress.rate = Some(((progress.bytes - from) as f64 / elapsed.as_secs_f64()) as u64);
+            progress.window = (Instant::now(), progress.bytes);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.entry.cancel.is_cancelled()
+    }
+
+    /// resolves once the host cancels this transfer
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.entry.cancel.cancelled()
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let mut entries = self.transfers.entries.lock().unwrap();
+        entries.retain(|x| x.id != self.entry.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(transfers: &Transfers, path: &str) -> Transfer {
+        transfers.start(Direction::Download, None, path.into(), Some(100))
+    }
+
+    #[test]
+    fn lists_until_dropped() {
+        let transfers = Transfers::default();
+        let a = start(&transfers, "a");
+        let b = start(&transfers, "b");
+        a.add(10);
+        let list = transfers.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].path.to_str(), list[0].bytes), (Some("a"), 10));
+        drop(a);
+        let list = transfers.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, b.entry.id);
+    }
+
+    #[test]
+    fn cancels_one_or_all() {
+        let transfers = Transfers::default();
+        let a = start(&transfers, "a");
+        let b = start(&transfers, "b");
+        let c = start(&transfers, "c");
+        transfers.cancel(b.entry.id);
+        assert!(!a.is_cancelled() && b.is_cancelled() && !c.is_cancelled());
+        // unknown ids are ignored
+        transfers.cancel(u64::MAX);
+        transfers.cancel_all();
+        assert!(a.is_cancelled() && c.is_cancelled());
+    }
+
+    #[test]
+    fn rates_come_from_the_last_window() {
+        let progress = |rate, opened: Duration, bytes, from| Progress {
+            bytes,
+            rate,
+            window: (Instant::now() - opened, from),
+        };
+        // a window still open shows the last closed one
+        let fresh = progress(Some(500), Duration::from_millis(100), 1000, 900);
+        assert_eq!(fresh.rate(), 500);
+        // a stalled window counts on its own
+        let stalled = progress(Some(500), Duration::from_secs(4), 1000, 0);
+        assert!((240..=250).contains(&stalled.rate()), "{}", stalled.rate());
+        let first = progress(None, Duration::from_secs(2), 1000, 0);
+        assert!((490..=500).contains(&first.rate()), "{}", first.rate());
+    }
+
+    #[test]
+    fn windows_close_as_bytes_come() {
+        let transfers = Transfers::default();
+        let a = start(&transfers, "a");
+        a.entry.progress.lock().unwrap().window = (Instant::now() - Duration::from_secs(2), 0);
+        a.add(2000);
+        let progress = a.entry.progress.lock().unwrap();
+        assert!(progress.rate.is_some_and(|x| (990..=1000).contains(&x)));
+        assert_eq!(progress.window.1, 2000);
+    }
+}
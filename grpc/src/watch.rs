@@ -0,0 +1,330 @@
+use crate::{
+    WatchChange,
+    nav::WatchResponse,
+    shares::{Share, Shares},
+    top::Unit,
+};
+use notify::{
+    Event, EventKind, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// one change to the direct children of a watched folder, paths as clients see them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created(Unit),
+    Removed(PathBuf),
+    Renamed { from: PathBuf, unit: Unit },
+    Modified(Unit),
+}
+
+impl Change {
+    /// brings a listing of the watched folder up to date, the order is left to the caller
+    pub fn apply(self, units: &mut Vec<Unit>) {
+        match self {
+            Change::Removed(path) => units.retain(|x| x.path != path),
+            Change::Renamed { from, unit } => {
+                units.retain(|x| x.path != from && x.path != unit.path);
+                units.push(unit);
+            }
+            Change::Created(unit) | Change::Modified(unit) => {
+                units.retain(|x| x.path != unit.path);
+                units.push(unit);
+            }
+        }
+    }
+}
+
+impl From<Change> for WatchResponse {
+    fn from(value: Change) -> Self {
+        let (change, unit, path) = match value {
+            Change::Created(unit) => (WatchChange::Created, Some(unit), None),
+            Change::Removed(path) => (WatchChange::Removed, None, Some(path)),
+            Change::Renamed { from, unit } => (WatchChange::Renamed, Some(unit), Some(from)),
+            Change::Modified(unit) => (WatchChange::Modified, Some(unit), None),
+        };
+        Self {
+            change: change.into(),
+            unit: unit.map(Into::into),
+            path: path
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<WatchResponse> for Change {
+    type Error = String;
+
+    fn try_from(value: WatchResponse) -> Result<Self, Self::Error> {
+        let change = value.change();
+        let Ok(path) = value.path.parse::<PathBuf>();
+        if change == WatchChange::Removed {
+            return Ok(Change::Removed(path));
+        }
+        let Some(unit) = value.unit.map(Unit::from) else {
+            return Err(format!("{change:?} change without its unit"));
+        };
+        Ok(match change {
+            WatchChange::Created => Change::Created(unit),
+            WatchChange::Renamed => Change::Renamed { from: path, unit },
+            _ => Change::Modified(unit),
+        })
+    }
+}
+
+/// sends the changes to what `path` holds as they happen, until the receiver is gone.
+///
+/// the top level only holds the shares which do not change while serving, its stream stays silent
+pub async fn watch(shares: &Shares, path: &Path) -> io::Result<ReceiverStream<io::Result<Change>>> {
+    let (tx, rx) = mpsc::channel(64);
+    if Shares::is_top(path) {
+        tokio::spawn(async move { tx.closed().await });
+        return Ok(ReceiverStream::new(rx));
+    }
+    let (share, dir) = shares.resolve(path).await?;
+    let share = share.clone();
+    // notify calls back from its own thread
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = events_tx.send(event);
+    })
+    .map_err(io::Error::other)?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        // dropping the watcher stops it
+        let _watcher = watcher;
+        loop {
+            let event = tokio::select! {
+                _ = tx.closed() => return,
+                event = events.recv() => event,
+            };
+            let changes = match event {
+                Some(Ok(event)) => changes(&share, &dir, event).await,
+                Some(Err(err)) => vec![Err(io::Error::other(err))],
+                None => return,
+            };
+            for change in changes {
+                if tx.send(change).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(ReceiverStream::new(rx))
+}
+
+async fn changes(share: &Share, dir: &Path, event: Event) -> Vec<io::Result<Change>> {
+    // events about the folder itself are not about what it holds
+    let paths: Vec<_> = event
+        .paths
+        .into_iter()
+        .filter(|x| x.parent() == Some(dir))
+        .collect();
+    let mut changes = Vec::new();
+    match (event.kind, paths.as_slice()) {
+        (EventKind::Access(_), _) => (),
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+            let Some(from) = share.relative(from) else {
+                return changes;
+            };
+            changes.push(Ok(match current(share, to).await {
+                Some(unit) => Change::Renamed { from, unit },
+                None => Change::Removed(from),
+            }));
+        }
+        (kind, paths) => {
+            for path in paths {
+                let Some(relative) = share.relative(path) else {
+                    continue;
+                };
+                let change = match (kind, current(share, path).await) {
+                    (_, None) => Change::Removed(relative),
+                    (
+                        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                        Some(unit),
+                    ) => Change::Created(unit),
+                    (_, Some(unit)) => Change::Modified(unit),
+                };
+                changes.push(Ok(change));
+            }
+        }
+    }
+    changes
+}
+
+/// the unit at `path` as clients see it, none once it is gone
+async fn current(share: &Share, path: &Path) -> Option<Unit> {
+    let unit = Unit::from_path(path.to_path_buf()).await.ok()?;
+    Some(Unit {
+        path: share.relative(path)?,
+        ..unit
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{UnitKind, top::UnitMeta};
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+    use std::fs;
+
+    /// a share named "x" holding `a.txt`, with a folder `out` next to it
+    fn shared_dir(name: &str) -> (PathBuf, Share) {
+        let base = std::env::temp_dir().join(format!("ours-watch-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("x")).unwrap();
+        fs::create_dir_all(base.join("out")).unwrap();
+        fs::write(base.join("x/a.txt"), b"a").unwrap();
+        let base = base.canonicalize().unwrap();
+        let share = Share {
+            name: String::from("x"),
+            path: base.join("x"),
+        };
+        (base, share)
+    }
+
+    async fn changes_of(share: &Share, kind: EventKind, paths: &[PathBuf]) -> Vec<Change> {
+        let event = paths
+            .iter()
+            .fold(Event::new(kind), |event, x| event.add_path(x.clone()));
+        changes(share, &share.path, event)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn unit(path: &str, size: u64) -> Unit {
+        Unit {
+            path: PathBuf::from(path),
+            kind: UnitKind::File,
+            meta: UnitMeta {
+                size,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn renames_inside_the_folder_keep_the_unit() {
+        let (base, share) = shared_dir("rename");
+        fs::rename(base.join("x/a.txt"), base.join("x/b.txt")).unwrap();
+        let kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let paths = [base.join("x/a.txt"), base.join("x/b.txt")];
+        let [Change::Renamed { from, unit }] = &changes_of(&share, kind, &paths).await[..] else {
+            panic!("a rename inside was not seen as one");
+        };
+        assert_eq!(from, Path::new("x/a.txt"));
+        assert_eq!(unit.path, Path::new("x/b.txt"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_out_of_the_folder_remove() {
+        let (base, share) = shared_dir("out");
+        fs::rename(base.join("x/a.txt"), base.join("out/a.txt")).unwrap();
+        let kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let paths = [base.join("x/a.txt"), base.join("out/a.txt")];
+        let [Change::Removed(path)] = &changes_of(&share, kind, &paths).await[..] else {
+            panic!("a rename out was not seen as a removal");
+        };
+        assert_eq!(path, Path::new("x/a.txt"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn creates_and_removes_follow_the_disk() {
+        let (base, share) = shared_dir("create");
+        fs::write(base.join("x/c.txt"), b"cc").unwrap();
+        let kind = EventKind::Create(CreateKind::File);
+        let [Change::Created(unit)] = &changes_of(&share, kind, &[base.join("x/c.txt")]).await[..]
+        else {
+            panic!("a new file was not seen as created");
+        };
+        assert_eq!(
+            (unit.path.as_path(), unit.meta.size),
+            (Path::new("x/c.txt"), 2)
+        );
+
+        fs::remove_file(base.join("x/a.txt")).unwrap();
+        let kind = EventKind::Remove(RemoveKind::File);
+        let [Change::Removed(path)] = &changes_of(&share, kind, &[base.join("x/a.txt")]).await[..]
+        else {
+            panic!("a removed file was not seen as removed");
+        };
+        assert_eq!(path, Path::new("x/a.txt"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_direct_children_count() {
+        let (base, share) = shared_dir("children");
+        let kind = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        assert!(changes_of(&share, kind, &[base.join("x")]).await.is_empty());
+        assert!(
+            changes_of(&share, kind, &[base.join("out/a.txt")])
+                .await
+                .is_empty()
+        );
+        let [Change::Modified(unit)] = &changes_of(&share, kind, &[base.join("x/a.txt")]).await[..]
+        else {
+            panic!("a written file was not seen as modified");
+        };
+        assert_eq!(unit.path, Path::new("x/a.txt"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn changes_bring_a_listing_up_to_date() {
+        let mut units = vec![unit("x/a", 1), unit("x/b", 1)];
+        Change::Created(unit("x/c", 1)).apply(&mut units);
+        Change::Modified(unit("x/a", 2)).apply(&mut units);
+        Change::Removed(PathBuf::from("x/b")).apply(&mut units);
+        Change::Renamed {
+            from: PathBuf::from("x/c"),
+            unit: unit("x/d", 1),
+        }
+        .apply(&mut units);
+        units.sort();
+        assert_eq!(units, [unit("x/a", 2), unit("x/d", 1)]);
+        // a rename over an existing name replaces it
+        Change::Renamed {
+            from: PathBuf::from("x/d"),
+            unit: unit("x/a", 1),
+        }
+        .apply(&mut units);
+        assert_eq!(units, [unit("x/a", 1)]);
+    }
+
+    #[test]
+    fn changes_survive_the_round_trip() {
+        let changes = [
+            Change::Created(unit("x/a", 1)),
+            Change::Removed(PathBuf::from("x/b")),
+            Change::Renamed {
+                from: PathBuf::from("x/c"),
+                unit: unit("x/d", 1),
+            },
+            Change::Modified(unit("x/e", 2)),
+        ];
+        for change in changes {
+            let back = Change::try_from(WatchResponse::from(change.clone())).unwrap();
+            assert_eq!(back, change);
+        }
+        let response = WatchResponse {
+            change: WatchChange::Created.into(),
+            unit: None,
+            path: String::new(),
+        };
+        assert!(Change::try_from(response).is_err());
+    }
+}
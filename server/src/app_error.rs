@@ -3,6 +3,7 @@ use axum::{
     extract::multipart::MultipartError,
     response::{self, IntoResponse},
 };
+use grpc::{thumbnail::ThumbnailError, transcode::TranscodeError};
 use serde::Serialize;
 use std::{
     fmt::Display,
@@ -23,6 +24,8 @@ pub enum ServerError {
     NonePathFilename,
     MultiPart(String),
     StripPrefixError,
+    Thumbnail(String),
+    TranscodeBusy,
 }
 
 impl From<JoinError> for ServerError {
@@ -49,6 +52,26 @@ impl From<StripPrefixError> for ServerError {
     }
 }
 
+impl From<ThumbnailError> for ServerError {
+    fn from(value: ThumbnailError) -> Self {
+        match value {
+            ThumbnailError::FfmpegSpawn(path) => Self::FfmpagSpawn(path),
+            ThumbnailError::FfmpegWait(path) => Self::FfmpagWait(path),
+            ThumbnailError::Io(err) => Self::Io(err.to_string()),
+            err => Self::Thumbnail(err.to_string()),
+        }
+    }
+}
+
+impl From<TranscodeError> for ServerError {
+    fn from(value: TranscodeError) -> Self {
+        match value {
+            TranscodeError::Busy => Self::TranscodeBusy,
+            TranscodeError::FfmpegSpawn(path) => Self::FfmpagSpawn(path),
+        }
+    }
+}
+
 impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let sj = serde_json::json!(self);
@@ -1,16 +1,28 @@
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use app_error::{ServerError, ServerResult};
 use axum::{
-    Router,
-    extract::DefaultBodyLimit,
-    routing::{get, get_service},
+    Extension, Router,
+    extract::{ConnectInfo, DefaultBodyLimit},
+    middleware,
+    routing::get,
 };
 use get_port::Ops;
-use tower_http::{cors::CorsLayer, services::ServeDir, timeout::TimeoutLayer};
+use grpc::{
+    ShareMode, auth::Pairing, shares::Shares, throttle::Limits, thumbnail::Thumbnails,
+    tls::Identity, transcode::Transcoder, transfers::Transfers,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
 use web::{
-    BOXESIN, Context, FAVICON, HTMX, TAILWIND,
-    media::{self, AUDIO_HREF, VIDEO_HREF},
+    ARCHIVE, BOXESIN, Context, FAVICON, HTMX, SEARCH, TAILWIND, THUMBNAIL, WATCH,
+    media::{self, AUDIO_HREF, MEDIA_INFO, TRANSCODE, VIDEO_HREF},
     utils::{self},
 };
 
@@ -26,18 +38,28 @@ mod assets_router;
 mod web_local;
 
 pub struct Server {
-    target: PathBuf,
+    shares: Shares,
     port: Option<u16>,
     timeout: Duration,
+    identity: Option<Identity>,
+    mode: ShareMode,
+    limits: Limits,
+    transfers: Transfers,
+    pairing: Option<Pairing>,
 }
 
 impl Server {
-    pub fn new(target: PathBuf) -> Self {
+    pub fn new(shares: Shares) -> Self {
         let port = get_port::tcp::TcpPort::any("0.0.0.0");
         Self {
-            target,
+            shares,
             port,
             timeout: Duration::from_secs(60 * 60),
+            identity: None,
+            mode: ShareMode::ReadWrite,
+            limits: Limits::default(),
+            transfers: Transfers::default(),
+            pairing: None,
         }
     }
     pub fn port(self, port: u16) -> Self {
@@ -49,43 +71,144 @@ impl Server {
     pub fn timeout(self, timeout: Duration) -> Self {
         Self { timeout, ..self }
     }
+    /// the web pages only read, so only a drop box changes what they serve
+    pub fn mode(self, mode: ShareMode) -> Self {
+        Self { mode, ..self }
+    }
+    /// paces downloads, pass a clone to share the limits with another server
+    pub fn limits(self, limits: Limits) -> Self {
+        Self { limits, ..self }
+    }
+    /// records downloads, pass a clone to list them with another server's
+    pub fn transfers(self, transfers: Transfers) -> Self {
+        Self { transfers, ..self }
+    }
+    /// pages showing the shares need this pairing token, see [`grpc::auth::Pairing`]
+    pub fn token(self, token: &str) -> Self {
+        Self {
+            pairing: Some(Pairing::new(token)),
+            ..self
+        }
+    }
+    /// serves https with this certificate instead of plain http
+    pub fn identity(self, identity: Identity) -> Self {
+        Self {
+            identity: Some(identity),
+            ..self
+        }
+    }
     pub async fn serve(self) -> ServerResult<()> {
         let Self {
-            target,
+            shares,
             port,
             timeout,
+            identity,
+            mode,
+            limits,
+            transfers,
+            pairing,
         } = self;
         let Some(port) = port else {
             return Err(ServerError::NonePort);
         };
         let addr = SocketAddr::from(([0; 4], port));
+        let shares = shares.canonicalize()?;
 
-        let target_dir = ServeDir::new(&target);
+        // everything that shows what is inside the share
+        let mut files = Router::new()
+            .route("/", get(web_local::index_page))
+            .route(VIDEO_HREF, get(web_local::videoplayer))
+            .route(AUDIO_HREF, get(web_local::audioplayer))
+            .route(
+                &format!("{}/{{*path}}", TRANSCODE),
+                get(web_local::transcode),
+            )
+            .route(
+                &format!("{}/{{*path}}", MEDIA_INFO),
+                get(web_local::media_info),
+            )
+            .route(&format!("{}/{{down}}", BOXESIN), get(web_local::boxes_in))
+            .route("/download/{*path}", get(web_local::download))
+            .route(&format!("{}/{{format}}", ARCHIVE), get(web_local::archive))
+            .route(SEARCH, get(web_local::search))
+            .route(WATCH, get(web_local::watch))
+            .route(
+                &format!("{}/{{*path}}", THUMBNAIL),
+                get(web_local::thumbnail),
+            )
+            .fallback(get(fallback))
+            .layer(middleware::from_fn_with_state(mode, web_local::readable));
+        if let Some(pairing) = pairing {
+            files = files.layer(middleware::from_fn_with_state(pairing, web_local::paired));
+        }
 
         let app = Router::new()
             .route(&utils::app_name_url(), get(self_executable))
-            .route("/", get(web_local::index_page))
             .route(TAILWIND, get(tailwind))
             .route("/icon/{name}", get(icon))
-            .route(VIDEO_HREF, get(web_local::videoplayer))
-            .route(AUDIO_HREF, get(web_local::audioplayer))
             .route(media::CLOSE_PLAYER, get(web_local::close_player))
             .route(HTMX, get(htmx))
             .route(FAVICON, get(favicon))
-            .route(&format!("{}/{{down}}", BOXESIN), get(web_local::boxes_in))
-            .fallback(get(fallback))
-            .nest_service("/download", get_service(target_dir))
-            .with_state(Context { target_dir: target })
+            .merge(files)
+            .with_state(Context {
+                shares,
+                thumbnails: Thumbnails::default(),
+                transcoder: Transcoder::default(),
+                limits,
+                transfers,
+            })
             .layer(TimeoutLayer::new(timeout))
             .layer(CorsLayer::permissive())
             .layer(DefaultBodyLimit::disable());
 
-        let listener = tokio::net::TcpListener::bind(&addr).await?;
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await?;
-        Ok(())
+        let listener = TcpListener::bind(&addr).await?;
+        match identity {
+            Some(identity) => serve_tls(listener, app, &identity).await,
+            None => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// the first wait after a failed accept, doubled on each failure in a row
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// connections that do not finish the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// axum::serve for tls streams, every accepted connection gets its own handshake task
+async fn serve_tls(listener: TcpListener, app: Router, identity: &Identity) -> ServerResult<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(identity.server_config()?));
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let (tcp, remote) = match listener.accept().await {
+            Ok(x) => x,
+            // mostly running out of file handles, retrying at once would spin until some close
+            Err(err) => {
+                eprintln!("can not accept a connection, retrying in {backoff:?}: {err}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF;
+        let acceptor = acceptor.clone();
+        let app = app.clone().layer(Extension(ConnectInfo(remote)));
+        tokio::spawn(async move {
+            // browsers drop the first handshake until the certificate is accepted
+            let Ok(Ok(tls)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await
+            else {
+                return;
+            };
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(tls), TowerToHyperService::new(app))
+                .await;
+        });
     }
 }
@@ -1,82 +1,355 @@
+use crate::app_error::ServerError;
 use axum::{
-    extract::{self, Query, State},
-    http::StatusCode,
-    response::Html,
+    body::Body,
+    extract::{
+        self, ConnectInfo, Query, State,
+        ws::{self, WebSocket, WebSocketUpgrade},
+    },
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
 };
 use axum_extra::{TypedHeader, headers::UserAgent};
-use common::{AUDIO_X, VIDEO_X};
-use grpc::{UnitKind, top::Unit};
-use std::path::PathBuf;
+use futures_util::{FutureExt, Stream, StreamExt};
+use grpc::{
+    ArchiveFormat, ShareMode, archive,
+    auth::{self, Pairing},
+    media,
+    search::{self, Filter},
+    shares::Shares,
+    throttle::Throttle,
+    top::Unit,
+    transfers::{Direction, Transfer},
+    watch::{self, Change},
+};
+use serde::Deserialize;
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::fs;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
 use web::{
     Context, IndexPage,
-    media::{AudioPlayerProps, HiddenPlayerProps, VideoPlayerProps},
+    media::{
+        AudioPlayerProps, HiddenPlayerProps, MediaInfoLineProps, VideoPlayerProps, VideoSource,
+    },
     utils::self_path,
 };
 
-use crate::app_error::ServerError;
-
 pub async fn boxes_in(
     Query(mut params): Query<Vec<(usize, String)>>,
     extract::Path(down): extract::Path<String>,
-    State(Context { target_dir }): State<Context>,
-) -> Html<String> {
+    State(Context { shares, .. }): State<Context>,
+) -> Result<Html<String>, StatusCode> {
     params.sort_by_key(|x| x.0);
     let parent = params.into_iter().map(|(_, x)| x).collect::<PathBuf>();
 
-    let path = target_dir.join(&parent);
-    let units = ls(path).await.unwrap();
+    let units = ls(&shares, &parent).await?;
 
     let is_downloadable = down == "down";
 
-    Html(
+    Ok(Html(
         web::BoxesProps {
             units,
-            target_dir,
             parent,
             is_downloadable,
+            live: true,
         }
         .to_html(),
-    )
+    ))
 }
 
-pub async fn fetch_data(
-    page: &mut IndexPage,
+/// the most results one search page shows
+const SEARCH_LIMIT: usize = 500;
+
+#[derive(Deserialize)]
+pub(crate) struct SearchParams {
+    q: String,
+    #[serde(default)]
     path: PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let units = ls(path).await?;
-    page.units = units;
-    Ok(())
 }
 
-pub(crate) async fn ls(root: PathBuf) -> Result<Vec<Unit>, ServerError> {
-    let mut dir = fs::read_dir(&root).await?;
+/// the units under `path` whose names hold `q`, shown in place of the listing
+pub(crate) async fn search(
+    Query(SearchParams { q, path }): Query<SearchParams>,
+    State(Context { shares, .. }): State<Context>,
+) -> Result<Html<String>, StatusCode> {
+    let filter = Filter::new(search::Query::new(q)).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut batches = search::walk(&shares, &path, None, Vec::new(), filter)
+        .await
+        .map_err(status)?;
     let mut units = Vec::new();
-    while let Some(x) = dir.next_entry().await? {
-        let kind = if x.file_type().await?.is_dir() {
-            UnitKind::Folder
-        } else {
-            let ex = x.path();
-            let ex = ex.extension().and_then(|x| x.to_str());
-            match ex {
-                Some(ex) => {
-                    if VIDEO_X.contains(&ex) {
-                        UnitKind::Video
-                    } else if AUDIO_X.contains(&ex) {
-                        UnitKind::Audio
-                    } else {
-                        UnitKind::File
-                    }
+    while let Some(batch) = batches.next().await {
+        units.extend(batch.map_err(status)?);
+        if units.len() >= SEARCH_LIMIT {
+            units.truncate(SEARCH_LIMIT);
+            break;
+        }
+    }
+    units.sort_by_key(|x| (x.kind, x.name()));
+    Ok(Html(
+        web::BoxesProps {
+            units,
+            parent: path,
+            is_downloadable: false,
+            live: false,
+        }
+        .to_html(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WatchParams {
+    #[serde(default)]
+    path: PathBuf,
+}
+
+/// tells the page over a websocket whenever what `path` holds changes
+pub(crate) async fn watch(
+    upgrade: WebSocketUpgrade,
+    Query(WatchParams { path }): Query<WatchParams>,
+    State(Context { shares, .. }): State<Context>,
+) -> Response {
+    match watch::watch(&shares, &path).await {
+        Ok(changes) => upgrade.on_upgrade(move |socket| send_changes(socket, changes)),
+        Err(err) => status(err).into_response(),
+    }
+}
+
+async fn send_changes(
+    mut socket: WebSocket,
+    mut changes: impl Stream<Item = io::Result<Change>> + Unpin,
+) {
+    loop {
+        tokio::select! {
+            change = changes.next() => {
+                match change {
+                    None => break,
+                    // one change that could not be read does not end the watch
+                    Some(Err(_)) => continue,
+                    Some(Ok(_)) => (),
+                }
+                // a burst of changes reloads the page once
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                while let Some(Some(_)) = changes.next().now_or_never() {}
+                if socket.send(ws::Message::Text("changed".into())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(_))) {
+                    break;
                 }
-                _ => UnitKind::File,
             }
-        };
-        let unit = Unit {
-            path: x.path().to_path_buf(),
-            kind,
-        };
-        units.push(unit);
+        }
+    }
+}
+
+fn status(err: io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn confine(shares: &Shares, path: &Path) -> Result<PathBuf, StatusCode> {
+    shares
+        .resolve(path)
+        .await
+        .map(|(_, path)| path)
+        .map_err(status)
+}
+
+/// serves ranges and conditional requests as they are, only the body is paced and tracked
+pub(crate) async fn download(
+    State(Context {
+        shares,
+        limits,
+        transfers,
+        ..
+    }): State<Context>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    extract::Path(shown): extract::Path<PathBuf>,
+    request: extract::Request,
+) -> Response {
+    let path = match confine(&shares, &shown).await {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+    let Ok(res) = ServeFile::new(path).oneshot(request).await;
+    let (parts, body) = res.into_response().into_parts();
+    if !parts.status.is_success() {
+        return Response::from_parts(parts, body);
+    }
+    // the length of the range when one was asked for
+    let total = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok());
+    let transfer = transfers.start(Direction::Download, Some(peer), shown, total);
+    let body = tracked(body, limits.throttle(Some(peer)), transfer);
+    Response::from_parts(parts, body)
+}
+
+/// paces `body` and counts it, a cancel from the host cuts the response short
+fn tracked(body: Body, throttle: Throttle, transfer: Transfer) -> Body {
+    let pace = Arc::new((throttle, transfer));
+    let body = body.into_data_stream().then(move |chunk| {
+        let pace = pace.clone();
+        async move {
+            let (throttle, transfer) = &*pace;
+            let chunk = chunk?;
+            tokio::select! {
+                biased;
+                _ = transfer.cancelled() => {
+                    return Err(axum::Error::new("the transfer was stopped by the host"));
+                }
+                _ = throttle.wait(chunk.len()) => (),
+            }
+            transfer.add(chunk.len());
+            Ok(chunk)
+        }
+    });
+    Body::from_stream(body)
+}
+
+/// a small jpeg preview of an image or video, the page falls back to an icon on errors
+pub(crate) async fn thumbnail(
+    State(Context {
+        shares, thumbnails, ..
+    }): State<Context>,
+    extract::Path(path): extract::Path<PathBuf>,
+) -> Response {
+    let path = match confine(&shares, &path).await {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+    match thumbnails.get(&path).await {
+        Ok(jpeg) => ([(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, ServerError::from(err)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TranscodeParams {
+    /// seconds into the video
+    #[serde(default)]
+    start: f64,
+}
+
+/// the video as fragmented mp4 from `start` on, ffmpeg is killed once the client goes away
+pub(crate) async fn transcode(
+    State(Context {
+        shares,
+        transcoder,
+        limits,
+        transfers,
+        ..
+    }): State<Context>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    extract::Path(shown): extract::Path<PathBuf>,
+    Query(TranscodeParams { start }): Query<TranscodeParams>,
+) -> Response {
+    let path = match confine(&shares, &shown).await {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+    let start = Duration::try_from_secs_f64(start).unwrap_or_default();
+    match transcoder.start(&path, start) {
+        Ok(output) => {
+            let transfer = transfers.start(Direction::Download, Some(peer), shown, None);
+            let body = tracked(
+                Body::from_stream(output),
+                limits.throttle(Some(peer)),
+                transfer,
+            );
+            ([(header::CONTENT_TYPE, "video/mp4")], body).into_response()
+        }
+        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, ServerError::from(err)).into_response(),
+    }
+}
+
+/// streams every `path` in the query as one archive built on the fly
+pub(crate) async fn archive(
+    State(Context {
+        shares,
+        limits,
+        transfers,
+        ..
+    }): State<Context>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    extract::Path(format): extract::Path<String>,
+    Query(params): Query<Vec<(String, PathBuf)>>,
+) -> Response {
+    let Some(format) = ArchiveFormat::from_extension(&format) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut paths = Vec::new();
+    let mut shown = Vec::new();
+    for (_, path) in params.into_iter().filter(|(key, _)| key == "path") {
+        match shares.expand(&path).await {
+            Ok(expanded) => paths.extend(expanded),
+            Err(err) => return status(err).into_response(),
+        }
+        shown.push(path.to_string_lossy().to_string());
     }
+    let name = match paths.as_slice() {
+        [path] => path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or("ours")
+            .replace('"', ""),
+        _ => String::from("ours"),
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", name, format.extension());
+    let body = Body::from_stream(archive::stream(shares, paths, format));
+    // the size is only known once packed
+    let transfer = transfers.start(
+        Direction::Download,
+        Some(peer),
+        PathBuf::from(shown.join(", ")),
+        None,
+    );
+    let body = tracked(body, limits.throttle(Some(peer)), transfer);
+    (
+        [
+            (header::CONTENT_TYPE, format.mime().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+pub async fn fetch_data(page: &mut IndexPage, shares: &Shares) -> Result<(), StatusCode> {
+    let units = ls(shares, &page.parent).await?;
+    page.units = units;
+    Ok(())
+}
+
+/// what `path` holds with paths as clients see them, the top level lists the shares
+pub(crate) async fn ls(shares: &Shares, path: &Path) -> Result<Vec<Unit>, StatusCode> {
+    let mut units = if Shares::is_top(path) {
+        shares.units().await
+    } else {
+        let (share, root) = shares.resolve(path).await.map_err(status)?;
+        let mut dir = fs::read_dir(&root).await.map_err(status)?;
+        let mut units = Vec::new();
+        while let Some(x) = dir.next_entry().await.map_err(status)? {
+            let unit = Unit::from_entry(&x).await.map_err(status)?;
+            if let Some(path) = share.relative(&unit.path) {
+                units.push(Unit { path, ..unit });
+            }
+        }
+        units
+    };
     units.sort_by_key(|x| (x.kind, x.name()));
     Ok(units)
 }
@@ -90,24 +363,40 @@ pub(crate) fn is_same_os(user_agent: UserAgent) -> bool {
 
 pub(crate) async fn index_page(
     TypedHeader(user_agent): TypedHeader<UserAgent>,
-    State(Context { target_dir }): State<Context>,
-) -> Html<String> {
+    State(Context { shares, .. }): State<Context>,
+) -> (StatusCode, Html<String>) {
     let same_os = is_same_os(user_agent);
-    let mut data = IndexPage::new(target_dir.clone(), same_os);
-    fetch_data(&mut data, target_dir).await.unwrap();
-    Html(data.render())
+    let mut data = IndexPage::new(PathBuf::new(), same_os);
+    match fetch_data(&mut data, &shares).await {
+        Ok(_) => (StatusCode::OK, Html(data.render())),
+        Err(status) => (status, Html(format!("<h2>{status}</h2>"))),
+    }
 }
 
-pub(crate) async fn videoplayer(
-    extract::Query(mut params): extract::Query<Vec<(usize, String)>>,
-) -> Html<String> {
+/// the player queries hold the path one component per index
+fn player_path(mut params: Vec<(usize, String)>) -> PathBuf {
     params.sort_by_key(|x| x.0);
-    let url = params
-        .into_iter()
-        .map(|(_, x)| x)
-        .fold(String::from("/download"), |acc, x| acc + "/" + &x);
+    params.into_iter().map(|(_, x)| x).collect()
+}
 
-    Html(VideoPlayerProps { url }.to_html())
+/// plays files browsers understand as they are and converts the rest
+pub(crate) async fn videoplayer(
+    State(Context { shares, .. }): State<Context>,
+    extract::Query(params): extract::Query<Vec<(usize, String)>>,
+) -> Html<String> {
+    let path = player_path(params);
+    let info = match confine(&shares, &path).await {
+        Ok(resolved) => media::probe(&resolved).await.ok(),
+        Err(_) => None,
+    };
+    // files that can not be probed are still tried as they are
+    let source = match info {
+        Some(info) if !info.browser_playable() => VideoSource::Transcoded {
+            duration: info.duration,
+        },
+        _ => VideoSource::Direct,
+    };
+    Html(VideoPlayerProps { path, source }.to_html())
 }
 
 pub async fn close_player() -> Html<String> {
@@ -115,20 +404,33 @@ pub async fn close_player() -> Html<String> {
 }
 
 pub(crate) async fn audioplayer(
-    extract::Query(mut params): extract::Query<Vec<(usize, String)>>,
+    extract::Query(params): extract::Query<Vec<(usize, String)>>,
 ) -> Html<String> {
-    params.sort_by_key(|x| x.0);
-    let url = params
-        .into_iter()
-        .map(|(_, x)| x)
-        .fold(String::from("/download"), |acc, x| acc + "/" + &x);
+    let path = player_path(params);
+    Html(AudioPlayerProps { path }.to_html())
+}
 
-    Html(AudioPlayerProps { url }.to_html())
+/// the line under the players, empty when the file can not be probed
+pub(crate) async fn media_info(
+    State(Context { shares, .. }): State<Context>,
+    extract::Path(path): extract::Path<PathBuf>,
+) -> (StatusCode, Html<String>) {
+    let resolved = match confine(&shares, &path).await {
+        Ok(resolved) => resolved,
+        Err(status) => return (status, Html(String::new())),
+    };
+    match media::probe(&resolved).await {
+        Ok(info) => (
+            StatusCode::OK,
+            Html(MediaInfoLineProps { info, path }.to_html()),
+        ),
+        Err(err) => (status(err), Html(String::new())),
+    }
 }
 
 pub(crate) async fn fallback(
     TypedHeader(user_agent): TypedHeader<UserAgent>,
-    State(Context { target_dir }): State<Context>,
+    State(Context { shares, .. }): State<Context>,
     reqwest: extract::Request,
 ) -> (StatusCode, Html<String>) {
     let mut path = reqwest.uri().path().to_string();
@@ -136,14 +438,76 @@ pub(crate) async fn fallback(
         path.remove(0);
     }
     let Ok(path) = path.parse::<PathBuf>();
-    let path = target_dir.join(path);
-    let mut page = IndexPage::new(target_dir, is_same_os(user_agent));
-    match fetch_data(&mut page, path).await {
+    let mut page = IndexPage::new(path, is_same_os(user_agent));
+    match fetch_data(&mut page, &shares).await {
         Ok(_) => (StatusCode::OK, Html(page.render())),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Html(format!("<h2>internal error {err:#?}</h2>")),
-        ),
+        Err(status) => (status, Html(format!("<h2>{status}</h2>"))),
+    }
+}
+
+const TOKEN_COOKIE: &str = "ours_token";
+
+#[derive(Deserialize)]
+struct TokenParams {
+    token: String,
+}
+
+/// the pages need the pairing token like the native clients do,
+/// it comes once in the link and is kept in a cookie from then on
+pub(crate) async fn paired(
+    State(pairing): State<Pairing>,
+    reqwest: extract::Request,
+    next: Next,
+) -> Response {
+    if let Ok(Query(TokenParams { token })) = Query::try_from_uri(reqwest.uri())
+        && pairing.accepts(&token)
+    {
+        // the token leaves the address bar and the browser history
+        let cookie = format!(
+            "{TOKEN_COOKIE}={}; Path=/; HttpOnly; SameSite=Strict",
+            auth::normalize(&token)
+        );
+        return (
+            StatusCode::SEE_OTHER,
+            [
+                (header::LOCATION, reqwest.uri().path().to_string()),
+                (header::SET_COOKIE, cookie),
+            ],
+        )
+            .into_response();
+    }
+    let paired = reqwest
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .filter_map(|x| x.trim().strip_prefix(TOKEN_COOKIE)?.strip_prefix('='))
+        .any(|x| pairing.accepts(x));
+    if paired {
+        next.run(reqwest).await
+    } else {
+        let status = StatusCode::UNAUTHORIZED;
+        let message = format!(
+            "<h2>{status} scan the code shown on the server or type its pairing token</h2>\
+             <form method=\"get\"><input name=\"token\" autofocus><button>pair</button></form>"
+        );
+        (status, Html(message)).into_response()
+    }
+}
+
+/// a drop box takes uploads only, its pages show nothing of what is inside
+pub(crate) async fn readable(
+    State(mode): State<ShareMode>,
+    reqwest: extract::Request,
+    next: Next,
+) -> Response {
+    if mode.can_read() {
+        next.run(reqwest).await
+    } else {
+        let status = StatusCode::FORBIDDEN;
+        let message = format!("<h2>{status} the share is in {} mode</h2>", mode.label());
+        (status, Html(message)).into_response()
     }
 }
 
@@ -160,3 +524,66 @@ pub(crate) async fn self_executable(
 
     (StatusCode::OK, contents)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, middleware, routing::get};
+
+    async fn fetch(uri: &str, cookie: Option<&str>) -> Response {
+        let app = Router::new()
+            .route("/page", get(|| async { "inside" }))
+            .layer(middleware::from_fn_with_state(
+                Pairing::new("ABCD-EFGH"),
+                paired,
+            ));
+        let mut req = extract::Request::builder().uri(uri);
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX);
+        String::from_utf8(bytes.await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn strangers_are_asked_for_the_token() {
+        for (uri, cookie) in [
+            ("/page", None),
+            ("/page?token=ABCD-EFGX", None),
+            ("/page?token=", None),
+            ("/page", Some("ours_token=ABCDEFGX")),
+            ("/page", Some("other_ours_token=ABCDEFGH")),
+        ] {
+            let response = fetch(uri, cookie).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{uri} {cookie:?}"
+            );
+            assert!(body(response).await.contains("pairing token"));
+        }
+    }
+
+    #[tokio::test]
+    async fn the_token_in_the_link_becomes_a_cookie() {
+        let response = fetch("/page?token=abcd-efgh", None).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let headers = response.headers();
+        assert_eq!(headers[header::LOCATION], "/page");
+        let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.contains("HttpOnly"));
+        let cookie = cookie.split(';').next().unwrap();
+        assert_eq!(cookie, format!("{TOKEN_COOKIE}=ABCDEFGH"));
+
+        let response = fetch("/page", Some(cookie)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "inside");
+        // among the other cookies of the site
+        let response = fetch("/page", Some(&format!("theme=dark; {cookie}"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
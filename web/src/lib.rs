@@ -2,15 +2,22 @@ pub const TAILWIND: &str = "/tailwind";
 pub const HTMX: &str = "/htmx";
 pub const FAVICON: &str = "/favicon.ico";
 pub const BOXESIN: &str = "/boxesin";
+pub const ARCHIVE: &str = "/archive";
+pub const SEARCH: &str = "/search";
+pub const WATCH: &str = "/watch";
+pub const THUMBNAIL: &str = "/thumbnail";
 const BOXESID: &str = "BOXES";
 
 use crate::{
     media::{HiddenPlayer, PLAYER_SECTION},
     navbar::{DownloadNativeApp, NavBar},
-    utils::path_as_query,
+    utils::{path_as_query, thumbnail_url, watch_url},
+};
+use common::{assets::IconName, format_size};
+use grpc::{
+    UnitKind, shares::Shares, throttle::Limits, thumbnail::Thumbnails, top::Unit,
+    transcode::Transcoder, transfers::Transfers,
 };
-use common::assets::IconName;
-use grpc::{UnitKind, top::Unit};
 use leptos::{either::Either, prelude::*};
 use std::path::PathBuf;
 
@@ -20,15 +27,15 @@ pub mod utils;
 
 pub struct IndexPage {
     same_os: bool,
-    pub target_dir: PathBuf,
+    pub parent: PathBuf,
     pub units: Vec<Unit>,
 }
 
 impl IndexPage {
-    pub fn new(root: PathBuf, same_os: bool) -> Self {
+    pub fn new(parent: PathBuf, same_os: bool) -> Self {
         Self {
             same_os,
-            target_dir: root,
+            parent,
             units: Vec::new(),
         }
     }
@@ -36,7 +43,7 @@ impl IndexPage {
     pub fn render(self) -> String {
         let IndexPage {
             units,
-            target_dir,
+            parent,
             same_os,
         } = self;
 
@@ -54,7 +61,7 @@ impl IndexPage {
                 <header>
                     <DownloadNativeApp same_os/>
                 </header>
-                <Boxes units target_dir parent={PathBuf::new()} is_downloadable={false}/>
+                <Boxes units parent is_downloadable={false} live={true}/>
                 <footer>
                     <HiddenPlayer/>
                 </footer>
@@ -72,17 +79,41 @@ impl BoxesProps {
 }
 
 #[component]
+/// `live` boxes reload whenever what `parent` holds changes on the server
 pub fn Boxes(
     units: Vec<Unit>,
-    target_dir: PathBuf,
     parent: PathBuf,
     is_downloadable: bool,
+    live: bool,
 ) -> impl IntoView {
+    let reload = live.then(|| {
+        let boxes = format!(
+            "{}/{}{}",
+            BOXESIN,
+            if is_downloadable { "down" } else { "nah" },
+            path_as_query(&parent)
+        );
+        // names may hold quotes, json strings are valid javascript
+        let quote = |x: String| serde_json::to_string(&x).unwrap_or_default();
+        let script = format!(
+            r##"(() => {{
+  window.oursWatch?.close();
+  const scheme = location.protocol === "https:" ? "wss://" : "ws://";
+  const ws = new WebSocket(scheme + location.host + {});
+  ws.onmessage = () => htmx.ajax("GET", {}, {{ target: "#{}", swap: "outerHTML" }});
+  window.oursWatch = ws;
+}})();"##,
+            quote(watch_url(&parent)),
+            quote(boxes),
+            BOXESID
+        );
+        view! { <script inner_html={script}></script> }
+    });
     let units_view = units
         .into_iter()
         .map(|unit| {
             view! {
-                <UnitComp unit=unit target_dir=target_dir.clone() is_downloadable/>
+                <UnitComp unit=unit is_downloadable/>
             }
         })
         .collect_view();
@@ -96,14 +127,16 @@ pub fn Boxes(
             >
                 {units_view}
             </div>
+            {reload}
         </main>
     }
 }
 
 #[component]
-fn UnitComp(unit: Unit, target_dir: PathBuf, is_downloadable: bool) -> impl IntoView {
+/// `unit.path` is relative to the top level, it starts with the share name
+fn UnitComp(unit: Unit, is_downloadable: bool) -> impl IntoView {
     let name = unit.name();
-    let path = unit.path.strip_prefix(target_dir).unwrap().to_path_buf();
+    let path = unit.path.clone();
 
     enum Hxs {
         File {
@@ -148,10 +181,17 @@ fn UnitComp(unit: Unit, target_dir: PathBuf, is_downloadable: bool) -> impl Into
         _ => None,
     };
 
+    let size = (unit.kind != UnitKind::Folder).then(|| {
+        view! {
+            <span class="block text-xs">{format_size(unit.meta.size)}</span>
+        }
+    });
+
     let children = view! {
         <div>
-            <Icon name={IconName::from(unit.kind)} />
+            <Preview unit={unit.clone()}/>
             <span>{name.clone()}</span>
+            {size}
         </div>
     };
 
@@ -181,6 +221,28 @@ fn UnitComp(unit: Unit, target_dir: PathBuf, is_downloadable: bool) -> impl Into
     }
 }
 
+#[component]
+/// the thumbnail of images and videos, the icon of their kind until it loads or when it can not
+fn Preview(unit: Unit) -> impl IntoView {
+    let iu: u8 = IconName::from(unit.kind).into();
+    if unit.has_thumbnail() {
+        Either::Left(view! {
+            <img
+                width="40"
+                height="40"
+                class="object-cover rounded"
+                loading="lazy"
+                src={thumbnail_url(&unit.path)}
+                onerror={format!("this.onerror=null;this.src='/icon/{iu}'")}
+            />
+        })
+    } else {
+        Either::Right(view! {
+            <Icon name={IconName::from(unit.kind)}/>
+        })
+    }
+}
+
 #[component]
 pub fn Icon(name: IconName) -> impl IntoView {
     let iu: u8 = name.into();
@@ -196,5 +258,9 @@ pub fn Icon(name: IconName) -> impl IntoView {
 
 #[derive(Clone)]
 pub struct Context {
-    pub target_dir: PathBuf,
+    pub shares: Shares,
+    pub thumbnails: Thumbnails,
+    pub transcoder: Transcoder,
+    pub limits: Limits,
+    pub transfers: Transfers,
 }
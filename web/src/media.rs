@@ -1,9 +1,23 @@
-use leptos::prelude::*;
+use crate::utils::{download_url, media_info_url, transcode_url};
+use grpc::media::MediaInfo;
+use leptos::{either::Either, prelude::*};
+use std::{path::PathBuf, time::Duration};
 
 pub const CLOSE_PLAYER: &str = "/CLOSE_PLAYER";
 pub const PLAYER_SECTION: &str = "PlayerSection";
 pub const VIDEO_HREF: &str = "/videoplay";
 pub const AUDIO_HREF: &str = "/audioplay";
+pub const MEDIA_INFO: &str = "/mediainfo";
+pub const TRANSCODE: &str = "/transcode";
+
+/// where the video player gets its stream from
+#[derive(Debug, Clone)]
+pub enum VideoSource {
+    /// the file as it is
+    Direct,
+    /// converted by the server, seeking restarts the conversion at the new time
+    Transcoded { duration: Option<Duration> },
+}
 
 impl VideoPlayerProps {
     pub fn to_html(self) -> String {
@@ -12,22 +26,51 @@ impl VideoPlayerProps {
 }
 
 #[component]
-pub fn VideoPlayer(url: String) -> impl IntoView {
+pub fn VideoPlayer(path: PathBuf, source: VideoSource) -> impl IntoView {
+    let video = match source {
+        VideoSource::Direct => Either::Left(view! {
+            <video width="100%" controls autoplay>
+                <source src={download_url(&path)}/>
+                Your browser does not support the video tag.
+            </video>
+        }),
+        VideoSource::Transcoded { duration } => {
+            let url = transcode_url(&path);
+            // a growing stream has no length to seek in, so the slider asks for a new one
+            let seek = duration.map(|duration| {
+                view! {
+                    <input
+                        type="range"
+                        class="w-full"
+                        min="0"
+                        max={duration.as_secs().to_string()}
+                        value="0"
+                        data-src={url.clone()}
+                        on:pointerdown=|ev| ev.stop_propagation()
+                        onchange="const video = this.previousElementSibling; video.src = this.dataset.src + '?start=' + this.value; video.play()"
+                    />
+                }
+            });
+            Either::Right(view! {
+                <video width="100%" controls autoplay src={url}>
+                    Your browser does not support the video tag.
+                </video>
+                {seek}
+            })
+        }
+    };
     view! {
-    <video
+    <div
         id={PLAYER_SECTION}
-        width="80%"
-        class="fixed top-5 left-1/2 transform -translate-x-1/2"
-        controls
-        autoplay
+        class="fixed top-5 left-1/2 transform -translate-x-1/2 w-4/5 bg-white"
         hx-get={CLOSE_PLAYER}
         hx-target="this"
         hx-swap="outerHTML"
         hx-trigger="pointerdown from:html"
     >
-        <source src={url} type="video/mp4"/>
-        Your browser does not support the video tag.
-    </video>
+        {video}
+        <MediaInfoLoader path/>
+    </div>
     }
 }
 impl AudioPlayerProps {
@@ -37,21 +80,59 @@ impl AudioPlayerProps {
 }
 
 #[component]
-pub fn AudioPlayer(url: String) -> impl IntoView {
+pub fn AudioPlayer(path: PathBuf) -> impl IntoView {
     view! {
-        <audio
+        <div
             id={PLAYER_SECTION}
-            class="fixed top-5 left-1/2 transform -translate-x-1/2"
-            controls
-            autoplay
+            class="fixed top-5 left-1/2 transform -translate-x-1/2 bg-white"
             hx-get={CLOSE_PLAYER}
             hx-target="this"
             hx-swap="outerHTML"
             hx-trigger="pointerdown from:html"
         >
-            <source src={url} type="audio/mp3"/>
-            Your browser does not support the video tag.
-        </audio>
+            <audio controls autoplay>
+                <source src={download_url(&path)} type="audio/mp3"/>
+                Your browser does not support the video tag.
+            </audio>
+            <MediaInfoLoader path/>
+        </div>
+    }
+}
+
+#[component]
+/// asks for the media info once the player is shown so probing never delays playback
+fn MediaInfoLoader(path: PathBuf) -> impl IntoView {
+    view! {
+        <p
+            hx-get={media_info_url(&path)}
+            hx-trigger="load"
+            hx-swap="outerHTML"
+        ></p>
+    }
+}
+
+impl MediaInfoLineProps {
+    pub fn to_html(self) -> String {
+        MediaInfoLine(self).to_html()
+    }
+}
+
+#[component]
+/// duration, codecs and size under the player, with the original for files browsers can not play
+pub fn MediaInfoLine(info: MediaInfo, path: PathBuf) -> impl IntoView {
+    let warning = (!info.browser_playable()).then(|| {
+        view! {
+            <p class="m-2 text-red-700">
+                "browsers can not play this file as it is, "
+                <a class="text-lime-700" href={download_url(&path)} download>"download the original"</a>
+            </p>
+        }
+    });
+    view! {
+        <div>
+            <p class="m-2 text-sm">{info.to_string()}</p>
+            {warning}
+        </div>
     }
 }
 
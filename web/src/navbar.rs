@@ -1,8 +1,9 @@
 use crate::{
-    BOXESID, BOXESIN, Icon,
-    utils::{app_name_url, path_as_query},
+    BOXESID, BOXESIN, Icon, SEARCH,
+    utils::{app_name_url, archive_url, path_as_query},
 };
 use common::assets::IconName;
+use grpc::ArchiveFormat;
 use leptos::{either::Either, prelude::*};
 use std::path::PathBuf;
 
@@ -10,13 +11,34 @@ use std::path::PathBuf;
 pub(crate) fn NavBar(parent: PathBuf, is_downloadable: bool) -> impl IntoView {
     view! {
         <div class="flex place-content-around m-2 p-2">
-            <DownloadButton is_downloadable parent/>
+            <DownloadButton is_downloadable parent=parent.clone()/>
+            <SearchBox parent/>
             <HomeButton/>
         </div>
 
     }
 }
 
+#[component]
+/// searches everything under `parent`, the results replace the boxes
+fn SearchBox(parent: PathBuf) -> impl IntoView {
+    view! {
+        <form
+            hx-get={SEARCH}
+            hx-target={format!("#{}",BOXESID)}
+            hx-swap="outerHTML"
+        >
+            <input type="hidden" name="path" value={parent.to_str().unwrap_or_default().to_string()}/>
+            <input
+                class="p-2 border-2 border-lime-500 rounded-lg"
+                type="search"
+                name="q"
+                placeholder="search"
+            />
+        </form>
+    }
+}
+
 #[component]
 pub(crate) fn HomeButton() -> impl IntoView {
     view! {
@@ -36,6 +58,13 @@ pub fn DownloadButton(is_downloadable: bool, parent: PathBuf) -> impl IntoView {
             >
                 <Icon name={IconName::Close}/>
             </button>
+            <a
+                href={archive_url(std::slice::from_ref(&parent), ArchiveFormat::Zip)}
+                title="download this folder"
+                download
+            >
+                <Icon name={IconName::Archive}/>
+            </a>
         })
     } else {
         Either::Left(view! {
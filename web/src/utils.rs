@@ -1,3 +1,8 @@
+use crate::{
+    ARCHIVE, THUMBNAIL, WATCH,
+    media::{MEDIA_INFO, TRANSCODE},
+};
+use grpc::ArchiveFormat;
 use std::{
     env::args,
     ffi::OsStr,
@@ -38,3 +43,64 @@ pub(crate) fn path_as_query(path: &Path) -> String {
         .map(kv)
         .fold(first, |acc, x| acc + "&&" + &x)
 }
+
+/// link to `paths` packed as one archive, the server names it after a lone path
+pub fn archive_url(paths: &[PathBuf], format: ArchiveFormat) -> String {
+    let query = paths
+        .iter()
+        .map(|x| format!("path={}", encode_query_value(x.to_str().unwrap())))
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}/{}?{}", ARCHIVE, format.extension(), query)
+}
+
+/// the websocket that tells when what `path` holds changes
+pub(crate) fn watch_url(path: &Path) -> String {
+    format!(
+        "{}?path={}",
+        WATCH,
+        encode_query_value(path.to_str().unwrap())
+    )
+}
+
+pub(crate) fn download_url(path: &Path) -> String {
+    format!("/download/{}", encode_query_value(path.to_str().unwrap()))
+}
+
+/// the query is left to the player, which adds the start time when seeking
+pub(crate) fn transcode_url(path: &Path) -> String {
+    format!(
+        "{}/{}",
+        TRANSCODE,
+        encode_query_value(path.to_str().unwrap())
+    )
+}
+
+pub(crate) fn media_info_url(path: &Path) -> String {
+    format!(
+        "{}/{}",
+        MEDIA_INFO,
+        encode_query_value(path.to_str().unwrap())
+    )
+}
+
+pub(crate) fn thumbnail_url(path: &Path) -> String {
+    format!(
+        "{}/{}",
+        THUMBNAIL,
+        encode_query_value(path.to_str().unwrap())
+    )
+}
+
+/// names may hold `&`, `#` or `?` which would cut the query short
+fn encode_query_value(x: &str) -> String {
+    x.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}